@@ -0,0 +1,128 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::mem::MaybeUninit;
+use std::ptr;
+use zeroize::Zeroize;
+
+//Workaround to self obfuscate (since we can't add ourselves as a dependency)
+#[allow(unused_imports)]
+use crate as r2d2;
+
+/*
+ * Global allocator that wipes memory before handing it back
+ * Decrypted strings and anything else that briefly held a secret otherwise sit in freed chunks
+ * until the allocator happens to reuse them
+ * Reallocations always move, since a realloc that moves the block in place of us would free the
+ * old one without wiping it, shrinking included, the inner allocator can move a block to a
+ * smaller size class
+ * That makes reallocating slower than with the plain system allocator
+ * Generated code installs it as the #[global_allocator] of binary crates
+ */
+pub struct ZeroizingAllocator<A: GlobalAlloc = System> {
+    inner: A,
+}
+
+impl ZeroizingAllocator<System> {
+    pub const fn new() -> Self {
+        ZeroizingAllocator { inner: System }
+    }
+}
+
+impl Default for ZeroizingAllocator<System> {
+    fn default() -> Self {
+        ZeroizingAllocator::new()
+    }
+}
+
+impl<A: GlobalAlloc> ZeroizingAllocator<A> {
+    pub const fn wrapping(inner: A) -> Self {
+        ZeroizingAllocator { inner }
+    }
+}
+
+/*
+ * SAFETY: ptr has to be valid for writes of len bytes
+ * MaybeUninit since the bytes may never have been initialized, zeroize writes are volatile so
+ * they survive the memory being freed right after
+ */
+unsafe fn wipe(ptr: *mut u8, len: usize) {
+    std::slice::from_raw_parts_mut(ptr as *mut MaybeUninit<u8>, len).zeroize();
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ZeroizingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        wipe(ptr, layout.size());
+        self.inner.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        //SAFETY: The caller guarantees new_size is valid for the alignment
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.inner.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod zeroizing_allocator_tests {
+    use crate::alloc::*;
+    use std::sync::Mutex;
+
+    //Remembers whether every block handed back to it was all zeroes
+    struct Recorder {
+        dirty: Mutex<usize>,
+    }
+
+    unsafe impl GlobalAlloc for Recorder {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let block = std::slice::from_raw_parts(ptr, layout.size());
+            if block.iter().any(|byte| *byte != 0) {
+                *self.dirty.lock().unwrap() += 1;
+            }
+            System.dealloc(ptr, layout);
+        }
+
+        //Never moves, anything that relies on it would free the old block unwiped
+        unsafe fn realloc(&self, ptr: *mut u8, _layout: Layout, _new_size: usize) -> *mut u8 {
+            *self.dirty.lock().unwrap() += 1;
+            ptr
+        }
+    }
+
+    #[test]
+    fn wipes_freed_memory() {
+        let allocator = ZeroizingAllocator::wrapping(Recorder {
+            dirty: Mutex::new(0),
+        });
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let block = allocator.alloc(layout);
+            ptr::write_bytes(block, 0x42, 64);
+            //Shrinking moves too, and wipes the whole old block
+            let block = allocator.realloc(block, layout, 16);
+            assert_eq!(std::slice::from_raw_parts(block, 16), &[0x42; 16]);
+            let layout = Layout::from_size_align(16, 8).unwrap();
+
+            //Growing moves to a new block and wipes the old one
+            let grown = allocator.realloc(block, layout, 128);
+            assert_eq!(std::slice::from_raw_parts(grown, 16), &[0x42; 16]);
+            allocator.dealloc(grown, Layout::from_size_align(128, 8).unwrap());
+        }
+        assert_eq!(*allocator.inner.dirty.lock().unwrap(), 0);
+    }
+}
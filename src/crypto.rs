@@ -1,30 +1,41 @@
-use digest::Digest;
+use digest::core_api::BlockSizeUser;
+use digest::{Digest, KeyInit, Mac};
 use generic_array;
 use generic_array::typenum::U0;
-use generic_array::typenum::U24;
 use generic_array::typenum::U32;
-use generic_array::typenum::U64;
 use generic_array::GenericArray;
 use rand;
 use rand::rngs::OsRng;
 use rand::RngCore;
-use std::alloc::dealloc;
-use std::alloc::Layout;
+use std::cell::UnsafeCell;
 use std::fmt::*;
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
-use std::mem::{size_of, ManuallyDrop};
+use std::mem::size_of;
 use std::ops::Deref;
 use std::ops::DerefMut;
 use std::ptr;
+use std::sync::{LockResult, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use typenum;
-use typenum::type_operators::IsEqual;
+use typenum::type_operators::{IsEqual, IsLessOrEqual};
 use typenum::True;
 use zeroize::Zeroize;
 
+use crate::protect::ProtectedPages;
+
+//Sealing encrypted containers for storage
+mod seal;
+pub use crate::crypto::seal::{SealCipher, SealError};
+
 //Public modules used in generated code
 pub use aead::{self, Aead, AeadInPlace, Key, NewAead, Nonce, Tag};
 pub use chacha20poly1305::{self, XChaCha20Poly1305};
-pub use blake2::Blake2b512;
+pub use aes_gcm::{self, Aes256Gcm};
+pub use chacha20::{self, ChaCha20};
+pub use blake2::{Blake2b512, Blake2bMac512, Blake2s256, Blake2sMac256};
+
+//BLAKE2b parameterized for a 256 bit output, not a truncated Blake2b512
+pub type Blake2b256 = blake2::Blake2b<U32>;
 
 //Workaround to self obfuscate (since we can't add ourselves as a dependency)
 #[allow(unused_imports)]
@@ -36,8 +47,6 @@ where
     Cipher: NewAead,
     Cipher: Aead,
     Cipher::KeySize: IsEqual<U32, Output = True>,
-    //TODO: Should probably redo nonce generation to be generic enough for things like AES-GCM that use 96 bit nonces
-    Cipher::NonceSize: IsEqual<U24, Output = True>,
 {
     pub key: Key<Cipher>,
     pub nonce: Nonce<Cipher>,
@@ -49,8 +58,6 @@ where
     Cipher: NewAead,
     Cipher: Aead,
     Cipher::KeySize: IsEqual<U32, Output = True>,
-    //TODO: Should probably redo nonce generation to be generic enough for things like AES-GCM that use 96 bit nonces
-    Cipher::NonceSize: IsEqual<U24, Output = True>,
 {
     let mut key: Key<Cipher> = Default::default();
     OsRng.fill_bytes(&mut key);
@@ -75,22 +82,298 @@ where
     Cipher: NewAead,
     Cipher: Aead,
     Cipher::KeySize: IsEqual<U32, Output = True>,
-    //TODO: Should probably redo nonce generation to be generic enough for things like AES-GCM that use 96 bit nonces
-    Cipher::NonceSize: IsEqual<U24, Output = True>,
+{
+    try_decrypt_memory(ctx).unwrap()
+}
+
+//Same as decrypt_memory, but hands authentication failures back to the caller
+pub fn try_decrypt_memory<Cipher>(
+    ctx: MemoryEncryptionCtx<Cipher>,
+) -> std::result::Result<Vec<u8>, aead::Error>
+where
+    Cipher: NewAead,
+    Cipher: Aead,
+    Cipher::KeySize: IsEqual<U32, Output = True>,
 {
     let cipher = Cipher::new(&ctx.key);
-    let output = cipher
-        .decrypt(&ctx.nonce, ctx.ciphertext.as_slice())
-        .unwrap();
+    let output = cipher.decrypt(&ctx.nonce, ctx.ciphertext.as_slice());
     //println!("We decrypted data: {:#x?}", output);
     output
 }
 
+//Size in bytes of each piece of a split master secret
+pub const KEY_SHARE_SIZE: usize = 32;
+
+//XOR the shares back together into the master secret
+fn combine_key_shares(shares: &[&[u8; KEY_SHARE_SIZE]]) -> [u8; KEY_SHARE_SIZE] {
+    let mut master = [0u8; KEY_SHARE_SIZE];
+    for share in shares {
+        for (i, byte) in master.iter_mut().enumerate() {
+            /*
+             * Shares live in immutable statics with known initializers
+             * Volatile reads stop the optimizer from folding them back into a single constant
+             */
+            *byte ^= unsafe { ptr::read_volatile(&share[i]) };
+        }
+    }
+    master
+}
+
+/*
+ * Expand a split master secret into 512 bits of key material for a single salt
+ * The recombined master secret is the BLAKE2b prefix key over the salt
+ */
+fn derive_key_material(shares: &[&[u8; KEY_SHARE_SIZE]], salt: &[u8]) -> Vec<u8> {
+    let mut master = combine_key_shares(shares);
+    let material = hash::<Blake2b512>(salt, Some(&master));
+    master.zeroize();
+    material
+}
+
+//Key comes from the front of the derived material, nonce from the bytes immediately after
+pub fn derive_memory_key<Cipher>(
+    shares: &[&[u8; KEY_SHARE_SIZE]],
+    salt: &[u8],
+) -> (Key<Cipher>, Nonce<Cipher>)
+where
+    Cipher: NewAead,
+    Cipher: Aead,
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    //Key and nonce are both carved out of a single 512 bit digest
+    Cipher::NonceSize: IsLessOrEqual<U32, Output = True>,
+{
+    let mut material = derive_key_material(shares, salt);
+
+    let key_size = size_of::<Key<Cipher>>();
+    let nonce_size = size_of::<Nonce<Cipher>>();
+    let key = Key::<Cipher>::clone_from_slice(&material[..key_size]);
+    let nonce = Nonce::<Cipher>::clone_from_slice(&material[key_size..key_size + nonce_size]);
+    material.zeroize();
+
+    (key, nonce)
+}
+
+pub fn encrypt_memory_derived<Cipher>(
+    shares: &[&[u8; KEY_SHARE_SIZE]],
+    salt: &[u8],
+    data: &[u8],
+) -> MemoryEncryptionCtx<Cipher>
+where
+    Cipher: NewAead,
+    Cipher: Aead,
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    //Key and nonce are both carved out of a single 512 bit digest
+    Cipher::NonceSize: IsLessOrEqual<U32, Output = True>,
+{
+    let (key, nonce) = derive_memory_key::<Cipher>(shares, salt);
+    let cipher = Cipher::new(&key);
+    let ciphertext = cipher.encrypt(&nonce, data).unwrap();
+
+    MemoryEncryptionCtx::<Cipher> {
+        key,
+        nonce,
+        ciphertext,
+    }
+}
+
+pub fn decrypt_memory_derived<Cipher>(
+    shares: &[&[u8; KEY_SHARE_SIZE]],
+    salt: &[u8],
+    ciphertext: &[u8],
+) -> Vec<u8>
+where
+    Cipher: NewAead,
+    Cipher: Aead,
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    //Key and nonce are both carved out of a single 512 bit digest
+    Cipher::NonceSize: IsLessOrEqual<U32, Output = True>,
+{
+    try_decrypt_memory_derived::<Cipher>(shares, salt, ciphertext).unwrap()
+}
+
+//Returns None instead of panicking when the ciphertext fails authentication
+pub fn try_decrypt_memory_derived<Cipher>(
+    shares: &[&[u8; KEY_SHARE_SIZE]],
+    salt: &[u8],
+    ciphertext: &[u8],
+) -> Option<Vec<u8>>
+where
+    Cipher: NewAead,
+    Cipher: Aead,
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    //Key and nonce are both carved out of a single 512 bit digest
+    Cipher::NonceSize: IsLessOrEqual<U32, Output = True>,
+{
+    let (key, nonce) = derive_memory_key::<Cipher>(shares, salt);
+    try_decrypt_memory(MemoryEncryptionCtx::<Cipher> {
+        key,
+        nonce,
+        ciphertext: Vec::from(ciphertext),
+    })
+    .ok()
+}
+
+/*
+ * Unauthenticated stream cipher encryption under a derived key
+ * Applying the keystream is its own inverse, so this handles both directions
+ */
+pub fn apply_keystream_derived<Cipher>(
+    shares: &[&[u8; KEY_SHARE_SIZE]],
+    salt: &[u8],
+    data: &[u8],
+) -> Vec<u8>
+where
+    Cipher: chacha20::cipher::NewCipher + chacha20::cipher::StreamCipher,
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    //Key and nonce are both carved out of a single 512 bit digest
+    Cipher::NonceSize: IsLessOrEqual<U32, Output = True>,
+{
+    let mut material = derive_key_material(shares, salt);
+
+    let key_size = size_of::<chacha20::cipher::CipherKey<Cipher>>();
+    let nonce_size = size_of::<chacha20::cipher::Nonce<Cipher>>();
+    let mut cipher = Cipher::new(
+        GenericArray::from_slice(&material[..key_size]),
+        GenericArray::from_slice(&material[key_size..key_size + nonce_size]),
+    );
+    material.zeroize();
+
+    let mut output = Vec::from(data);
+    cipher.apply_keystream(&mut output);
+    output
+}
+
+/*
+ * Rolling XOR against an LCG keystream seeded from the master secret and salt
+ * Offers no real cryptographic strength, it only exists so very hot paths can avoid a hash and
+ * cipher setup per decryption
+ * Like any XOR stream, this handles both directions
+ */
+pub fn rolling_xor_derived(
+    shares: &[&[u8; KEY_SHARE_SIZE]],
+    salt: &[u8],
+    data: &[u8],
+) -> Vec<u8> {
+    let mut master = combine_key_shares(shares);
+    let mut state: u64 = 0;
+    for byte in master.iter().chain(salt) {
+        state = state.rotate_left(8) ^ u64::from(*byte);
+        state = state.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+    master.zeroize();
+
+    let output = data
+        .iter()
+        .map(|byte| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            byte ^ (state >> 56) as u8
+        })
+        .collect();
+    state.zeroize();
+    output
+}
+
+/*
+ * Owned plaintext produced by string decryption
+ * Behaves like a &str for reading, but wipes the backing buffer on drop so decrypted literals
+ * don't linger in freed heap memory
+ */
+#[derive(Clone, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SecretString {
+    data: String,
+}
+
+impl SecretString {
+    pub fn as_str(&self) -> &str {
+        self.data.as_str()
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        //String::zeroize wipes the full capacity, not just the initialized length
+        self.data.zeroize();
+    }
+}
+
+impl Deref for SecretString {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        self.data.as_str()
+    }
+}
+
+impl AsRef<str> for SecretString {
+    fn as_ref(&self) -> &str {
+        self.data.as_str()
+    }
+}
+
+impl AsRef<[u8]> for SecretString {
+    fn as_ref(&self) -> &[u8] {
+        self.data.as_bytes()
+    }
+}
+
+impl std::borrow::Borrow<str> for SecretString {
+    fn borrow(&self) -> &str {
+        self.data.as_str()
+    }
+}
+
+impl Display for SecretString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        Display::fmt(self.data.as_str(), f)
+    }
+}
+
+impl Debug for SecretString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        //Needs to match &str formatting, since this replaces string literals in {:?} arguments
+        Debug::fmt(self.data.as_str(), f)
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(data: String) -> Self {
+        SecretString { data }
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(data: &str) -> Self {
+        SecretString {
+            data: String::from(data),
+        }
+    }
+}
+
+impl From<SecretString> for String {
+    fn from(mut secret: SecretString) -> Self {
+        //Leaves an empty string behind for the drop to zero
+        std::mem::take(&mut secret.data)
+    }
+}
+
+impl PartialEq<str> for SecretString {
+    fn eq(&self, other: &str) -> bool {
+        self.data.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for SecretString {
+    fn eq(&self, other: &&str) -> bool {
+        self.data.as_str() == *other
+    }
+}
+
+//Any digest, the salt is hashed as a prefix of the data
 pub fn hash<Hash>(data: &[u8], salt: Option<&[u8]>) -> Vec<u8>
 where
     Hash: Digest,
-    //We only want 512 bit hashes because why not?
-    Hash::OutputSize: IsEqual<U64, Output = True>,
 {
     let mut h = match salt {
         Some(salt) => Hash::new_with_prefix(salt),
@@ -100,32 +383,174 @@ where
     Vec::from(h.finalize().as_slice())
 }
 
+/*
+ * MACs with their own keyed mode, like Blake2bMac512
+ * BLAKE2 keys can't be longer than the hash output, anything longer panics
+ */
+pub fn keyed_hash<MacImpl>(key: &[u8], data: &[u8]) -> Vec<u8>
+where
+    MacImpl: Mac + KeyInit,
+{
+    let mut mac = <MacImpl as KeyInit>::new_from_slice(key).unwrap();
+    Mac::update(&mut mac, data);
+    Vec::from(mac.finalize().into_bytes().as_slice())
+}
+
+const HMAC_IPAD: u8 = 0x36;
+const HMAC_OPAD: u8 = 0x5c;
+
+//RFC 2104 HMAC over any block based digest, for digests without a keyed mode of their own
+pub fn hmac<Hash>(key: &[u8], data: &[u8]) -> Vec<u8>
+where
+    Hash: Digest + BlockSizeUser,
+{
+    //Keys longer than a block are hashed down first, shorter ones zero padded
+    let mut block_key = vec![0u8; Hash::block_size()];
+    if key.len() > block_key.len() {
+        let mut hashed = Hash::digest(key);
+        block_key[..hashed.len()].copy_from_slice(&hashed);
+        hashed.zeroize();
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut pad: Vec<u8> = block_key.iter().map(|byte| byte ^ HMAC_IPAD).collect();
+    let mut inner = Hash::new_with_prefix(&pad);
+    inner.update(data);
+    let inner_hash = inner.finalize();
+
+    pad.iter_mut()
+        .zip(block_key.iter())
+        .for_each(|(pad, byte)| *pad = byte ^ HMAC_OPAD);
+    let mut outer = Hash::new_with_prefix(&pad);
+    outer.update(inner_hash);
+
+    block_key.zeroize();
+    pad.zeroize();
+    Vec::from(outer.finalize().as_slice())
+}
+
+/*
+ * Hash picked at runtime, for places where the obfuscator and generated code have to agree on
+ * one, like integrity checks
+ * The 256 bit BLAKE2s variants are the faster choice for large inputs on 32 bit targets, on
+ * 64 bit ones Blake2b256 keeps the BLAKE2b speed with the smaller output, which is easier to hide
+ * Keyed variants use the salt as the key
+ */
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    Blake2b512,
+    Blake2b256,
+    Blake2s256,
+    Blake2bMac512,
+    Blake2sMac256,
+    HmacBlake2b512,
+    HmacBlake2s256,
+}
+
+impl HashAlgorithm {
+    pub fn output_size(self) -> usize {
+        match self {
+            HashAlgorithm::Blake2b512
+            | HashAlgorithm::Blake2bMac512
+            | HashAlgorithm::HmacBlake2b512 => 64,
+            HashAlgorithm::Blake2b256
+            | HashAlgorithm::Blake2s256
+            | HashAlgorithm::Blake2sMac256
+            | HashAlgorithm::HmacBlake2s256 => 32,
+        }
+    }
+
+    pub fn hash(self, data: &[u8], salt: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Blake2b512 => hash::<Blake2b512>(data, Some(salt)),
+            HashAlgorithm::Blake2b256 => hash::<Blake2b256>(data, Some(salt)),
+            HashAlgorithm::Blake2s256 => hash::<Blake2s256>(data, Some(salt)),
+            HashAlgorithm::Blake2bMac512 => keyed_hash::<Blake2bMac512>(salt, data),
+            HashAlgorithm::Blake2sMac256 => keyed_hash::<Blake2sMac256>(salt, data),
+            HashAlgorithm::HmacBlake2b512 => hmac::<Blake2b512>(salt, data),
+            HashAlgorithm::HmacBlake2s256 => hmac::<Blake2s256>(salt, data),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Hash, PartialEq)]
 enum EncBoxState {
     Decrypted,
     Encrypted,
 }
 
-//TODO: Add memory protections (locking, RWX permissions, etc)
-#[derive(Clone, Hash, PartialEq)]
+//Why an encrypted container couldn't be opened
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum EncBoxError {
+    //Already decrypted, only reachable by leaking a guard instead of dropping it
+    WrongState,
+    //The ciphertext, tag or key was modified behind the container's back
+    AuthenticationFailed,
+}
+
+impl Display for EncBoxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            EncBoxError::WrongState => write!(f, "container is not encrypted"),
+            EncBoxError::AuthenticationFailed => write!(f, "container failed authentication"),
+        }
+    }
+}
+
+impl std::error::Error for EncBoxError {}
+
+//Keeps ratchet output from colliding with any other use of BLAKE2b on the same bytes
+const RATCHET_DOMAIN: &[u8] = b"r2d2 container ratchet";
+
+/*
+ * Replaces a key and nonce with the next pair in the chain, a one way function of the current one
+ * Every re-encryption uses a fresh key, so the nonce would be safe to reuse, deriving it anyway
+ * keeps two encryptions from ever sharing a key+nonce combo
+ * Only the first pair comes from OsRng, the rest cost one hash rather than a syscall
+ */
+fn ratchet_key<Cipher>(key: &mut Key<Cipher>, nonce: &mut Nonce<Cipher>)
+where
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+{
+    let mut hasher = Blake2b512::new_with_prefix(RATCHET_DOMAIN);
+    hasher.update(key.as_slice());
+    hasher.update(nonce.as_slice());
+    let mut output = hasher.finalize();
+
+    //The key takes the first half, AEAD nonces are far shorter than the second
+    let (next_key, next_nonce) = output.split_at(key.len());
+    let nonce_size = nonce.len();
+    key.copy_from_slice(next_key);
+    nonce.copy_from_slice(&next_nonce[..nonce_size]);
+    output.zeroize();
+}
+
+/*
+ * Encrypted container for a single value
+ * The value lives alone on dedicated pages, see ProtectedPages, which are inaccessible whenever
+ * the value is encrypted
+ */
 pub struct EncBox<T, Cipher>
 where
     T: Sized + ToOwned<Owned = T>,
     Cipher: NewAead + AeadInPlace,
     //Enforce 256 bit keys
     Cipher::KeySize: IsEqual<U32, Output = True>,
-    //We assume no excess bytes in ciphertext, so Box<T> can hold the encrypted data, don't need to
-    //allocate extra data or handle that unsafe mess
+    //We assume no excess bytes in ciphertext, so the pages sized for T can hold the encrypted
+    //data, don't need to allocate extra data or handle that unsafe mess
     Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
 {
     _marker: PhantomData<T>,
     /*
-     * Can't use an array because of const_generics
-     * Can't use GenericArray because of const_convert preventing size_of->UInt conversion
-     * Box<T> only works due to constraint that CiphertextOverhead == 0
-     * ManuallyDrop<Box<T>> is to inhibit the box drop so we can manually drop it with memory zeroing
+     * Holds a single T at the start of the first page
+     * Raw pages rather than a Box<T> so we control the protections, and so we can manually drop
+     * the value with memory zeroing
      */
-    data: ManuallyDrop<Box<T>>,
+    data: ProtectedPages,
     key: Key<Cipher>,
     nonce: Nonce<Cipher>,
     tag: Tag<Cipher>,
@@ -142,65 +567,117 @@ where
     Cipher::KeySize: IsEqual<U32, Output = True>,
     Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
 {
+    fn value(&self) -> &T {
+        //SAFETY: The pages always hold an initialized T, page alignment covers any T
+        unsafe { &*(self.data.as_ptr() as *const T) }
+    }
+
+    fn value_mut(&mut self) -> &mut T {
+        //SAFETY: Same as value
+        unsafe { &mut *(self.data.as_ptr() as *mut T) }
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        //SAFETY: The bytes of the T at the start of the pages
+        unsafe { std::slice::from_raw_parts_mut(self.data.as_ptr(), size_of::<T>()) }
+    }
+
+    //Re-encrypts in place under the next key in the chain, see ratchet_key
     fn ratchet_underlying(&mut self) {
         debug_assert!(self.state == EncBoxState::Decrypted);
-        *self = Self::from(&**self.data);
+        ratchet_key::<Cipher>(&mut self.key, &mut self.nonce);
+        self.encrypt_underlying();
     }
 
-    //Only failure is decryption related, which intentionally panics
-    fn decrypt_underlying(&mut self) {
+    fn encrypt_underlying(&mut self) {
         let keyed = Cipher::new(&self.key);
-        let dest: &mut [u8] = unsafe {
-            std::slice::from_raw_parts_mut(
-                std::ptr::addr_of_mut!(**self.data) as *mut u8,
-                size_of::<T>(),
-            )
-        };
-
-        keyed
-            .decrypt_in_place_detached(&self.nonce, &usize::to_be_bytes(self.aad), dest, &self.tag)
+        let (nonce, aad) = (self.nonce.to_owned(), self.aad);
+        self.tag = keyed
+            .encrypt_in_place_detached(&nonce, &usize::to_be_bytes(aad), self.bytes())
             .unwrap();
+        self.state = EncBoxState::Encrypted;
+        self.data.set_accessible(false);
+    }
+
+    //The ciphers check the tag before touching the buffer, so a failure leaves the ciphertext
+    fn decrypt_underlying(&mut self) -> std::result::Result<(), EncBoxError> {
+        let keyed = Cipher::new(&self.key);
+        let (nonce, aad, tag) = (self.nonce.to_owned(), self.aad, self.tag.to_owned());
+        self.data.set_accessible(true);
+
+        if keyed
+            .decrypt_in_place_detached(&nonce, &usize::to_be_bytes(aad), self.bytes(), &tag)
+            .is_err()
+        {
+            self.data.set_accessible(false);
+            return Err(EncBoxError::AuthenticationFailed);
+        }
         self.state = EncBoxState::Decrypted;
+        Ok(())
     }
 
+    pub fn try_decrypt(&mut self) -> std::result::Result<EncBoxGuard<'_, T, Cipher>, EncBoxError> {
+        if self.state != EncBoxState::Encrypted {
+            return Err(EncBoxError::WrongState);
+        }
+        self.decrypt_underlying()?;
+        Ok(EncBoxGuard { encbox: self })
+    }
+
+    //Panics where try_decrypt would fail, tampering isn't something callers can recover from
     pub fn decrypt(&mut self) -> EncBoxGuard<'_, T, Cipher> {
-        //TODO: Enforce precondition with Result<T>, rather than asserting
+        self.try_decrypt().unwrap()
+    }
+
+    /*
+     * Shared access to the value for Clone, PartialEq and Hash, which only get &self
+     * The ciphertext is decrypted into scratch pages rather than in place, and the scratch copy
+     * is wiped without running its destructor, the box still owns everything the value points to
+     */
+    fn peek<R>(&self, f: impl FnOnce(&T) -> R) -> R {
         debug_assert!(self.state == EncBoxState::Encrypted);
-        self.decrypt_underlying();
-        EncBoxGuard { encbox: self }
+        let scratch = ProtectedPages::new(size_of::<T>());
+        self.data.read(|data| {
+            //SAFETY: Both allocations hold at least size_of::<T>() bytes
+            unsafe { ptr::copy_nonoverlapping(data.as_ptr(), scratch.as_ptr(), size_of::<T>()) };
+        });
+
+        //SAFETY: The bytes of the T copied to the start of the scratch pages
+        let bytes = unsafe { std::slice::from_raw_parts_mut(scratch.as_ptr(), size_of::<T>()) };
+        Cipher::new(&self.key)
+            .decrypt_in_place_detached(&self.nonce, &usize::to_be_bytes(self.aad), bytes, &self.tag)
+            .expect("EncBox failed authentication");
+        //SAFETY: Authenticated, so this is a bitwise copy of the boxed T, only ever borrowed
+        f(unsafe { &*(scratch.as_ptr() as *const T) })
     }
 
+    //Whether the value's pages are kept out of swap, locking fails when RLIMIT_MEMLOCK runs out
+    pub fn is_locked(&self) -> bool {
+        self.data.is_locked()
+    }
+
+    //Only for the first encryption, every later key+nonce combo is ratcheted from this one
     fn generate_nonce() -> Nonce<Cipher> {
-        /*
-         * We always ratchet when re-encrypting, which generates a new key+nonce combo, so randomly
-         * generating them is fine, neither key nor nonce will be reused
-         */
         let mut nonce: Nonce<Cipher> = GenericArray::default();
         OsRng.fill_bytes(nonce.as_mut_slice());
         nonce
     }
 
     pub fn new(data: T) -> EncBox<T, Cipher> {
-        let mut ret = EncBox {
+        let pages = ProtectedPages::new(size_of::<T>());
+        //SAFETY: Fresh pages big enough and aligned for a T
+        unsafe { ptr::write(pages.as_ptr() as *mut T, data) };
+
+        let mut ret: EncBox<T, Cipher> = EncBox {
             _marker: PhantomData,
-            data: ManuallyDrop::new(Box::new(data)),
+            data: pages,
             key: Cipher::generate_key(OsRng),
             nonce: Self::generate_nonce(),
             tag: GenericArray::default(),
             aad: size_of::<T>(),
             state: EncBoxState::Decrypted,
         };
-        let dest: &mut [u8] = unsafe {
-            std::slice::from_raw_parts_mut(
-                std::ptr::addr_of_mut!(**ret.data) as *mut u8,
-                size_of::<T>(),
-            )
-        };
-        let keyed = Cipher::new(&ret.key);
-        ret.tag = keyed
-            .encrypt_in_place_detached(&ret.nonce, &usize::to_be_bytes(ret.aad), dest)
-            .unwrap();
-        ret.state = EncBoxState::Encrypted;
+        ret.encrypt_underlying();
         ret
     }
 }
@@ -214,40 +691,29 @@ where
     Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
 {
     fn drop(&mut self) {
-        if self.state == EncBoxState::Encrypted {
-            self.decrypt_underlying();
-        }
+        /*
+         * A value that fails authentication is garbage, running its destructor could free
+         * arbitrary pointers, so it gets leaked instead and only the pages are wiped
+         */
+        let intact = self.state == EncBoxState::Decrypted || self.decrypt_underlying().is_ok();
         //Zero out the underlying data
         self.key.zeroize();
         self.nonce.zeroize();
         self.tag.zeroize();
         self.aad.zeroize();
 
-        /*
-         * SAFETY: This is necessary to zero out the contents of the data pointer effectively
-         * Box::zeroize doesn't work when T isn't Default+Copy
-         * If we zeroize the underlying bytes manually, it leaks (or worse!) due to our drop being
-         * called prior to actual member drop
-         */
-        unsafe {
-            //Needed to work around mutable reference preventing moving data in directly
-            let raw_data = ManuallyDrop::take(&mut self.data);
-            //Grab the raw pointer out of the box
-            let data_ptr = Box::into_raw(raw_data);
-            //Treat the raw pointer as a u8 slice we can zero
-            let byte_data_slice: &mut [u8] =
-                std::slice::from_raw_parts_mut(data_ptr as *mut u8, size_of::<T>());
-            //Call the destructor for the box
-            ptr::drop_in_place(data_ptr);
-            //Zero out the data
-            byte_data_slice.zeroize();
-            //Free the underlying
-            dealloc(data_ptr as *mut u8, Layout::new::<T>());
+        if intact {
+            /*
+             * SAFETY: The value is initialized and never touched again
+             * Its own destructor runs first, so anything it owns gets freed, then the pages
+             * themselves zero every byte on drop
+             */
+            unsafe { ptr::drop_in_place(self.data.as_ptr() as *mut T) };
         }
     }
 }
 
-impl<T, Cipher> Deref for EncBox<T, Cipher>
+impl<T, Cipher> From<T> for EncBox<T, Cipher>
 where
     T: Sized + ToOwned<Owned = T>,
     Cipher: NewAead + AeadInPlace,
@@ -255,15 +721,12 @@ where
     Cipher::KeySize: IsEqual<U32, Output = True>,
     Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
 {
-    type Target = T;
-
-    fn deref(&self) -> &Self::Target {
-        debug_assert!(self.state == EncBoxState::Decrypted);
-        self.data.as_ref()
+    fn from(data: T) -> Self {
+        EncBox::<T, Cipher>::new(data)
     }
 }
 
-impl<T, Cipher> DerefMut for EncBox<T, Cipher>
+impl<T, Cipher> From<&T> for EncBox<T, Cipher>
 where
     T: Sized + ToOwned<Owned = T>,
     Cipher: NewAead + AeadInPlace,
@@ -271,13 +734,16 @@ where
     Cipher::KeySize: IsEqual<U32, Output = True>,
     Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
 {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        debug_assert!(self.state == EncBoxState::Decrypted);
-        self.data.as_mut()
+    fn from(data: &T) -> Self {
+        EncBox::<T, Cipher>::new(data.to_owned())
     }
 }
 
-impl<T, Cipher> From<T> for EncBox<T, Cipher>
+/*
+ * Clones get a fresh key, copying the key chain along with the ciphertext would have both boxes
+ * encrypt different values under the same key+nonce after their next ratchet
+ */
+impl<T, Cipher> Clone for EncBox<T, Cipher>
 where
     T: Sized + ToOwned<Owned = T>,
     Cipher: NewAead + AeadInPlace,
@@ -285,21 +751,35 @@ where
     Cipher::KeySize: IsEqual<U32, Output = True>,
     Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
 {
-    fn from(data: T) -> Self {
-        EncBox::<T, Cipher>::new(data)
+    fn clone(&self) -> Self {
+        EncBox::<T, Cipher>::new(self.peek(T::to_owned))
     }
 }
 
-impl<T, Cipher> From<&T> for EncBox<T, Cipher>
+//Compares the values, every box has its own key so the ciphertexts never match
+impl<T, Cipher> PartialEq for EncBox<T, Cipher>
 where
-    T: Sized + ToOwned<Owned = T>,
+    T: Sized + ToOwned<Owned = T> + PartialEq,
     Cipher: NewAead + AeadInPlace,
     //Enforce 256 bit keys
     Cipher::KeySize: IsEqual<U32, Output = True>,
     Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
 {
-    fn from(data: &T) -> Self {
-        EncBox::<T, Cipher>::new(data.to_owned())
+    fn eq(&self, other: &Self) -> bool {
+        self.peek(|value| other.peek(|other| value == other))
+    }
+}
+
+impl<T, Cipher> Hash for EncBox<T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T> + Hash,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.peek(|value| value.hash(state))
     }
 }
 
@@ -327,7 +807,6 @@ where
     }
 }
 
-#[derive(Hash, PartialEq)]
 pub struct EncBoxGuard<'a, T, Cipher>
 where
     T: Sized + ToOwned<Owned = T> + 'a,
@@ -365,7 +844,7 @@ where
 
     fn deref(&self) -> &Self::Target {
         debug_assert!(self.encbox.state == EncBoxState::Decrypted);
-        self.encbox.data.as_ref()
+        self.encbox.value()
     }
 }
 
@@ -379,7 +858,33 @@ where
 {
     fn deref_mut(&mut self) -> &mut Self::Target {
         debug_assert!(self.encbox.state == EncBoxState::Decrypted);
-        self.encbox.data.as_mut()
+        self.encbox.value_mut()
+    }
+}
+
+impl<'a, T, Cipher> PartialEq for EncBoxGuard<'a, T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T> + PartialEq,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<'a, T, Cipher> Hash for EncBoxGuard<'a, T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T> + Hash,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
     }
 }
 
@@ -398,21 +903,895 @@ where
     }
 }
 
-#[cfg(test)]
-mod memory_encryption_tests {
-    use crate::crypto::*;
-    #[test]
-    fn check_decryption() {
-        let mut data = [0u8; 128];
-        OsRng.fill_bytes(&mut data);
-        let ctx = encrypt_memory::<XChaCha20Poly1305>(&data);
-        let plaintext = decrypt_memory::<XChaCha20Poly1305>(ctx);
-        assert_eq!(&data, plaintext.as_slice());
-    }
-}
-
-#[cfg(test)]
-mod enc_box_tests {
+/*
+ * EncBox shared between threads
+ * Any number of readers share one decrypted view, writers get the value to themselves
+ * The first reader in decrypts, and the value is re-encrypted and ratcheted as soon as the last
+ * guard of either kind drops, so it's only ever plaintext while someone is looking at it
+ * Like RwLock, read and write block until the lock is available
+ */
+pub struct EncRwLock<T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T>,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    //Held shared by readers and exclusively by writers
+    access: RwLock<()>,
+    //Readers holding the decrypted view, only touched with access held
+    readers: Mutex<usize>,
+    //Mutated by writers, or by readers bringing the count to or from zero under readers
+    encbox: UnsafeCell<EncBox<T, Cipher>>,
+}
+
+//SAFETY: Access to the box is synchronized as described on the fields, same bounds as RwLock
+unsafe impl<T, Cipher> Sync for EncRwLock<T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T> + Send + Sync,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+}
+
+/*
+ * Guards re-encrypt while unwinding, so a panicking holder never leaves the value decrypted and
+ * poisoning carries no information for us
+ */
+fn ignore_poison<G>(result: LockResult<G>) -> G {
+    result.unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<T, Cipher> EncRwLock<T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T>,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    pub fn new(data: T) -> EncRwLock<T, Cipher> {
+        EncRwLock::from(EncBox::new(data))
+    }
+
+    pub fn into_inner(self) -> EncBox<T, Cipher> {
+        self.encbox.into_inner()
+    }
+
+    pub fn try_read(&self) -> std::result::Result<EncReadGuard<'_, T, Cipher>, EncBoxError> {
+        let access = ignore_poison(self.access.read());
+        let mut readers = ignore_poison(self.readers.lock());
+        if *readers == 0 {
+            //SAFETY: No other reader holds the view, and writers are locked out by access
+            unsafe { (*self.encbox.get()).decrypt_underlying()? };
+        }
+        *readers += 1;
+        Ok(EncReadGuard {
+            lock: self,
+            _access: access,
+        })
+    }
+
+    //Panics where try_read would fail, see EncBox::decrypt
+    pub fn read(&self) -> EncReadGuard<'_, T, Cipher> {
+        self.try_read().unwrap()
+    }
+
+    pub fn try_write(&self) -> std::result::Result<EncWriteGuard<'_, T, Cipher>, EncBoxError> {
+        let access = ignore_poison(self.access.write());
+        //SAFETY: access is held exclusively, so nothing else refers to the box
+        let encbox = unsafe { &mut *self.encbox.get() };
+        Ok(EncWriteGuard {
+            guard: encbox.try_decrypt()?,
+            _access: access,
+        })
+    }
+
+    //Panics where try_write would fail, see EncBox::decrypt
+    pub fn write(&self) -> EncWriteGuard<'_, T, Cipher> {
+        self.try_write().unwrap()
+    }
+}
+
+impl<T, Cipher> From<T> for EncRwLock<T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T>,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn from(data: T) -> Self {
+        EncRwLock::new(data)
+    }
+}
+
+impl<T, Cipher> From<EncBox<T, Cipher>> for EncRwLock<T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T>,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn from(encbox: EncBox<T, Cipher>) -> Self {
+        EncRwLock {
+            access: RwLock::new(()),
+            readers: Mutex::new(0),
+            encbox: UnsafeCell::new(encbox),
+        }
+    }
+}
+
+impl<T, Cipher> Debug for EncRwLock<T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T>,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        //The box itself can't be looked at without taking the lock
+        f.debug_struct("EncRwLock")
+            .field("readers", &*ignore_poison(self.readers.lock()))
+            .finish()
+    }
+}
+
+pub struct EncReadGuard<'a, T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T> + 'a,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    lock: &'a EncRwLock<T, Cipher>,
+    //Released after the reader count is dropped in Drop
+    _access: RwLockReadGuard<'a, ()>,
+}
+
+impl<'a, T, Cipher> Drop for EncReadGuard<'a, T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T>,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn drop(&mut self) {
+        let mut readers = ignore_poison(self.lock.readers.lock());
+        *readers -= 1;
+        if *readers == 0 {
+            //SAFETY: We were the last reader, and writers are still locked out by access
+            unsafe { (*self.lock.encbox.get()).ratchet_underlying() };
+        }
+    }
+}
+
+impl<'a, T, Cipher> Deref for EncReadGuard<'a, T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T>,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        //SAFETY: The box stays decrypted and unmodified while any reader holds a guard
+        unsafe { (*self.lock.encbox.get()).value() }
+    }
+}
+
+impl<'a, T, Cipher> Debug for EncReadGuard<'a, T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T>,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("EncReadGuard")
+            .field("lock", &self.lock)
+            .finish()
+    }
+}
+
+pub struct EncWriteGuard<'a, T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T> + 'a,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    //Fields drop in order, so the value is re-encrypted before the lock is released
+    guard: EncBoxGuard<'a, T, Cipher>,
+    _access: RwLockWriteGuard<'a, ()>,
+}
+
+impl<'a, T, Cipher> Deref for EncWriteGuard<'a, T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T>,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<'a, T, Cipher> DerefMut for EncWriteGuard<'a, T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T>,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<'a, T, Cipher> Debug for EncWriteGuard<'a, T, Cipher>
+where
+    T: Sized + ToOwned<Owned = T>,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("EncWriteGuard")
+            .field("guard", &self.guard)
+            .finish()
+    }
+}
+
+/*
+ * Types whose values are nothing but their bytes: no padding, no pointers, and every bit pattern
+ * is valid, so they can be viewed as a byte slice and encrypted or decrypted in place
+ * Sealed, Copy alone would let in references, bools and chars
+ */
+pub trait Pod: Copy + pod::Sealed {}
+
+mod pod {
+    pub trait Sealed {}
+}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(
+            impl pod::Sealed for $t {}
+            impl Pod for $t {}
+        )*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+//Arrays have no padding between elements
+impl<T: Pod, const N: usize> pod::Sealed for [T; N] {}
+impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/*
+ * EncBox only encrypts the size_of::<T>() bytes it owns, which for a String or Vec is nothing
+ * but the pointer, length and capacity, the contents sit in a separate heap buffer
+ * EncVec encrypts that buffer in place instead
+ * The length stays readable while encrypted, and is authenticated as the AAD
+ * Elements are Pod so they can't own buffers of their own that would stay plaintext, and
+ * their bytes can be encrypted in place without producing invalid values
+ */
+pub struct EncVec<T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    data: Vec<T>,
+    key: Key<Cipher>,
+    nonce: Nonce<Cipher>,
+    tag: Tag<Cipher>,
+    state: EncBoxState,
+}
+
+//Overwrite the whole allocation, including spare capacity that may hold stale plaintext
+fn wipe_vec<T: Pod>(data: &mut Vec<T>) {
+    unsafe {
+        //SAFETY: T is Pod, so forgetting the elements doesn't skip any destructors
+        data.set_len(0);
+    }
+    let spare = data.spare_capacity_mut();
+    //SAFETY: Any bit pattern is a valid MaybeUninit<u8>, and the length covers the same bytes
+    let bytes: &mut [std::mem::MaybeUninit<u8>] = unsafe {
+        std::slice::from_raw_parts_mut(
+            spare.as_mut_ptr() as *mut std::mem::MaybeUninit<u8>,
+            spare.len() * size_of::<T>(),
+        )
+    };
+    bytes.zeroize();
+}
+
+impl<T, Cipher> EncVec<T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    //Takes the buffer itself, so the only plaintext copy is the one being encrypted
+    pub fn new(data: Vec<T>) -> EncVec<T, Cipher> {
+        let mut ret = EncVec {
+            data,
+            key: Cipher::generate_key(OsRng),
+            nonce: GenericArray::default(),
+            tag: GenericArray::default(),
+            state: EncBoxState::Decrypted,
+        };
+        OsRng.fill_bytes(ret.nonce.as_mut_slice());
+        ret.encrypt_underlying();
+        ret
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn aad(&self) -> [u8; size_of::<usize>()] {
+        usize::to_be_bytes(self.data.len())
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        //SAFETY: The initialized elements of the buffer, viewed as bytes
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                self.data.as_mut_ptr() as *mut u8,
+                self.data.len() * size_of::<T>(),
+            )
+        }
+    }
+
+    fn encrypt_underlying(&mut self) {
+        debug_assert!(self.state == EncBoxState::Decrypted);
+        let keyed = Cipher::new(&self.key);
+        let (nonce, aad) = (self.nonce.to_owned(), self.aad());
+        self.tag = keyed
+            .encrypt_in_place_detached(&nonce, &aad, self.bytes())
+            .unwrap();
+        self.state = EncBoxState::Encrypted;
+    }
+
+    //Every re-encryption moves on to the next key in the chain, see ratchet_key
+    fn ratchet_underlying(&mut self) {
+        ratchet_key::<Cipher>(&mut self.key, &mut self.nonce);
+        self.encrypt_underlying();
+    }
+
+    fn decrypt_underlying(&mut self) -> std::result::Result<(), EncBoxError> {
+        let keyed = Cipher::new(&self.key);
+        let (nonce, aad, tag) = (self.nonce.to_owned(), self.aad(), self.tag.to_owned());
+        keyed
+            .decrypt_in_place_detached(&nonce, &aad, self.bytes(), &tag)
+            .map_err(|_| EncBoxError::AuthenticationFailed)?;
+        self.state = EncBoxState::Decrypted;
+        Ok(())
+    }
+
+    pub fn try_decrypt(&mut self) -> std::result::Result<EncVecGuard<'_, T, Cipher>, EncBoxError> {
+        if self.state != EncBoxState::Encrypted {
+            return Err(EncBoxError::WrongState);
+        }
+        self.decrypt_underlying()?;
+        Ok(EncVecGuard { encvec: self })
+    }
+
+    //Panics where try_decrypt would fail, see EncBox::decrypt
+    pub fn decrypt(&mut self) -> EncVecGuard<'_, T, Cipher> {
+        self.try_decrypt().unwrap()
+    }
+}
+
+impl<T, Cipher> Drop for EncVec<T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn drop(&mut self) {
+        //Nothing to decrypt first, the elements have no destructors to run
+        wipe_vec(&mut self.data);
+        self.key.zeroize();
+        self.nonce.zeroize();
+        self.tag.zeroize();
+    }
+}
+
+impl<T, Cipher> From<&[T]> for EncVec<T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn from(data: &[T]) -> Self {
+        EncVec::new(data.to_vec())
+    }
+}
+
+impl<T, Cipher> From<Vec<T>> for EncVec<T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn from(data: Vec<T>) -> Self {
+        EncVec::new(data)
+    }
+}
+
+impl<T, Cipher> Debug for EncVec<T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("EncVec")
+            .field("len", &self.data.len())
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+/*
+ * Decrypted view of an EncVec, re-encrypted under the next key when dropped
+ * Growing goes through the guard, so a reallocation never leaves plaintext behind in the old
+ * buffer
+ */
+pub struct EncVecGuard<'a, T, Cipher>
+where
+    T: Pod + 'a,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    encvec: &'a mut EncVec<T, Cipher>,
+}
+
+impl<'a, T, Cipher> EncVecGuard<'a, T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    //Move into a bigger buffer by hand when needed, wiping the old one
+    fn reserve(&mut self, additional: usize) {
+        let data = &mut self.encvec.data;
+        let needed = data.len() + additional;
+        if needed <= data.capacity() {
+            return;
+        }
+        let mut grown: Vec<T> = Vec::with_capacity(needed.max(data.capacity() * 2));
+        grown.extend_from_slice(data);
+        wipe_vec(data);
+        *data = grown;
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.reserve(1);
+        self.encvec.data.push(value);
+    }
+
+    pub fn extend_from_slice(&mut self, values: &[T]) {
+        self.reserve(values.len());
+        self.encvec.data.extend_from_slice(values);
+    }
+
+    //Removed elements are wiped along with the rest of the spare capacity
+    pub fn truncate(&mut self, len: usize) {
+        let data = &mut self.encvec.data;
+        if len >= data.len() {
+            return;
+        }
+        let mut kept: Vec<T> = Vec::with_capacity(data.capacity());
+        kept.extend_from_slice(&data[..len]);
+        wipe_vec(data);
+        *data = kept;
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+}
+
+impl<'a, T, Cipher> Drop for EncVecGuard<'a, T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn drop(&mut self) {
+        self.encvec.ratchet_underlying();
+    }
+}
+
+impl<'a, T, Cipher> Deref for EncVecGuard<'a, T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        debug_assert!(self.encvec.state == EncBoxState::Decrypted);
+        self.encvec.data.as_slice()
+    }
+}
+
+impl<'a, T, Cipher> DerefMut for EncVecGuard<'a, T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        debug_assert!(self.encvec.state == EncBoxState::Decrypted);
+        self.encvec.data.as_mut_slice()
+    }
+}
+
+impl<'a, T, Cipher> Debug for EncVecGuard<'a, T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("EncVecGuard")
+            .field("encvec", &self.encvec)
+            .finish()
+    }
+}
+
+//EncVec<u8> that only ever holds valid UTF-8, see EncVec
+pub struct EncString<Cipher>
+where
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    inner: EncVec<u8, Cipher>,
+}
+
+impl<Cipher> EncString<Cipher>
+where
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    pub fn new(data: String) -> EncString<Cipher> {
+        EncString {
+            inner: EncVec::new(data.into_bytes()),
+        }
+    }
+
+    //Length in bytes
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn try_decrypt(&mut self) -> std::result::Result<EncStringGuard<'_, Cipher>, EncBoxError> {
+        Ok(EncStringGuard {
+            inner: self.inner.try_decrypt()?,
+        })
+    }
+
+    pub fn decrypt(&mut self) -> EncStringGuard<'_, Cipher> {
+        self.try_decrypt().unwrap()
+    }
+}
+
+impl<Cipher> From<String> for EncString<Cipher>
+where
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn from(data: String) -> Self {
+        EncString::new(data)
+    }
+}
+
+impl<Cipher> From<&str> for EncString<Cipher>
+where
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn from(data: &str) -> Self {
+        EncString::new(String::from(data))
+    }
+}
+
+impl<Cipher> Debug for EncString<Cipher>
+where
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("EncString")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+pub struct EncStringGuard<'a, Cipher>
+where
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    inner: EncVecGuard<'a, u8, Cipher>,
+}
+
+impl<'a, Cipher> EncStringGuard<'a, Cipher>
+where
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    pub fn as_str(&self) -> &str {
+        //SAFETY: Only ever built from a String, and only ever modified a str at a time
+        unsafe { std::str::from_utf8_unchecked(&self.inner) }
+    }
+
+    pub fn as_mut_str(&mut self) -> &mut str {
+        //SAFETY: Same as as_str, and &mut str can only be modified in UTF-8 preserving ways
+        unsafe { std::str::from_utf8_unchecked_mut(&mut self.inner) }
+    }
+
+    pub fn push_str(&mut self, string: &str) {
+        self.inner.extend_from_slice(string.as_bytes());
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.push_str(c.encode_utf8(&mut [0u8; 4]));
+    }
+
+    //Panics if len doesn't fall on a char boundary, like String::truncate
+    pub fn truncate(&mut self, len: usize) {
+        assert!(self.as_str().is_char_boundary(len));
+        self.inner.truncate(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+}
+
+impl<'a, Cipher> Deref for EncStringGuard<'a, Cipher>
+where
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl<'a, Cipher> DerefMut for EncStringGuard<'a, Cipher>
+where
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.as_mut_str()
+    }
+}
+
+impl<'a, Cipher> Debug for EncStringGuard<'a, Cipher>
+where
+    Cipher: NewAead + AeadInPlace,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.debug_struct("EncStringGuard")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod memory_encryption_tests {
+    use crate::crypto::*;
+    #[test]
+    fn check_decryption() {
+        let mut data = [0u8; 128];
+        OsRng.fill_bytes(&mut data);
+        let ctx = encrypt_memory::<XChaCha20Poly1305>(&data);
+        let plaintext = decrypt_memory::<XChaCha20Poly1305>(ctx);
+        assert_eq!(&data, plaintext.as_slice());
+    }
+}
+
+#[cfg(test)]
+mod derived_encryption_tests {
+    use crate::crypto::*;
+    #[test]
+    fn check_decryption() {
+        let mut data = [0u8; 128];
+        OsRng.fill_bytes(&mut data);
+        let mut shares = [[0u8; KEY_SHARE_SIZE]; 3];
+        shares.iter_mut().for_each(|share| OsRng.fill_bytes(share));
+        let share_refs: Vec<&[u8; KEY_SHARE_SIZE]> = shares.iter().collect();
+
+        let ctx = encrypt_memory_derived::<XChaCha20Poly1305>(&share_refs, b"salt", &data);
+        let plaintext =
+            decrypt_memory_derived::<XChaCha20Poly1305>(&share_refs, b"salt", &ctx.ciphertext);
+        assert_eq!(&data, plaintext.as_slice());
+
+        //Same secret under a different salt must produce a different key
+        let (key, nonce) = derive_memory_key::<XChaCha20Poly1305>(&share_refs, b"pepper");
+        assert_ne!(key, ctx.key);
+        assert_ne!(nonce, ctx.nonce);
+
+        //96 bit nonces work just as well as the extended ones
+        let ctx = encrypt_memory_derived::<Aes256Gcm>(&share_refs, b"salt", &data);
+        let plaintext = decrypt_memory_derived::<Aes256Gcm>(&share_refs, b"salt", &ctx.ciphertext);
+        assert_eq!(&data, plaintext.as_slice());
+
+        //Tampering is reported rather than panicking
+        let mut tampered = ctx.ciphertext.to_owned();
+        tampered[0] ^= 1;
+        assert!(try_decrypt_memory_derived::<Aes256Gcm>(&share_refs, b"salt", &tampered).is_none());
+    }
+}
+
+#[cfg(test)]
+mod derived_stream_tests {
+    use crate::crypto::*;
+    #[test]
+    fn check_decryption() {
+        let mut data = [0u8; 128];
+        OsRng.fill_bytes(&mut data);
+        let mut shares = [[0u8; KEY_SHARE_SIZE]; 3];
+        shares.iter_mut().for_each(|share| OsRng.fill_bytes(share));
+        let share_refs: Vec<&[u8; KEY_SHARE_SIZE]> = shares.iter().collect();
+
+        let ciphertext = apply_keystream_derived::<ChaCha20>(&share_refs, b"salt", &data);
+        assert_ne!(&data, ciphertext.as_slice());
+        let plaintext = apply_keystream_derived::<ChaCha20>(&share_refs, b"salt", &ciphertext);
+        assert_eq!(&data, plaintext.as_slice());
+
+        let ciphertext = rolling_xor_derived(&share_refs, b"salt", &data);
+        assert_ne!(&data, ciphertext.as_slice());
+        let plaintext = rolling_xor_derived(&share_refs, b"salt", &ciphertext);
+        assert_eq!(&data, plaintext.as_slice());
+    }
+}
+
+#[cfg(test)]
+mod secret_string_tests {
+    use crate::crypto::*;
+    #[test]
+    fn behaves_like_str() {
+        let secret = SecretString::from("FizzBuzz");
+        assert_eq!(secret, "FizzBuzz");
+        assert_eq!(secret.len(), 8);
+        assert_eq!(format!("{}", secret), "FizzBuzz");
+        assert_eq!(format!("{:?}", secret), format!("{:?}", "FizzBuzz"));
+        assert_eq!(String::from(secret), "FizzBuzz".to_string());
+    }
+}
+
+#[cfg(test)]
+mod keyed_hash_tests {
+    use crate::crypto::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    //Reference values from Python's hmac and hashlib
+    #[test]
+    fn known_answers() {
+        let (key, data) = (b"r2d2 key", b"The quick brown fox");
+        assert_eq!(
+            hex(&hmac::<Blake2s256>(key, data)),
+            "3c735e1fd5f684adeaed83595e03aae3eac628176835f8cc0ad6e5a06994a522"
+        );
+        assert_eq!(
+            hex(&hmac::<Blake2s256>(&[b'k'; 200], data)),
+            "fc5b92a55f3e5d5943c7fbdfbbae208b20aaeb8af602ae15942c85d9059326d8"
+        );
+        assert_eq!(
+            hex(&HashAlgorithm::HmacBlake2b512.hash(data, key)),
+            "e0467098fe414a81cd4c01047830807a72a8c65ba94c102613cd234033cdbdd2\
+             5c056ab14989836cfeb0d79025338dd61aa2748b7fc2a8cb496a50cbf4fff1c8"
+        );
+        assert_eq!(
+            hex(&HashAlgorithm::Blake2sMac256.hash(data, key)),
+            "a364e416f209045d12c088f41fcf8bb26573773fbb87861c87e4f57cb1bc5f6a"
+        );
+        assert_eq!(
+            hex(&hash::<Blake2s256>(data, None)),
+            "d91e3f27d04ad78e7395da9f800a349a046f60c5047c576faf02df63c5ce1e4b"
+        );
+        assert_eq!(
+            hex(&hash::<Blake2b256>(data, None)),
+            "ede12169334c6077da458f82643f0663448d5b638033a56746a741509ce312c0"
+        );
+
+        for algorithm in [
+            HashAlgorithm::Blake2b512,
+            HashAlgorithm::Blake2b256,
+            HashAlgorithm::Blake2bMac512,
+        ] {
+            assert_eq!(algorithm.hash(data, key).len(), algorithm.output_size());
+        }
+    }
+}
+
+#[cfg(test)]
+mod enc_box_tests {
     use crate::crypto::*;
     #[test]
     fn sanity_check() {
@@ -424,4 +1803,171 @@ mod enc_box_tests {
         assert_eq!(basic, *contents);
         assert_eq!(*modified.decrypt(), "FiyyBuyy");
     }
+
+    #[test]
+    fn fallible_decrypt() {
+        let mut enc: EncBox<String, XChaCha20Poly1305> = EncBox::from("FizzBuzz".to_string());
+        assert_eq!(*enc.try_decrypt().unwrap(), "FizzBuzz");
+
+        //Leaking the guard skips re-encryption
+        std::mem::forget(enc.try_decrypt().unwrap());
+        assert_eq!(enc.try_decrypt().unwrap_err(), EncBoxError::WrongState);
+
+        let mut enc: EncBox<u64, Aes256Gcm> = EncBox::new(42);
+        enc.tag[0] ^= 1;
+        assert_eq!(
+            enc.try_decrypt().unwrap_err(),
+            EncBoxError::AuthenticationFailed
+        );
+        //Failing leaves the ciphertext intact
+        enc.tag[0] ^= 1;
+        assert_eq!(*enc.decrypt(), 42);
+
+        //Dropping a tampered box must not run the destructor on garbage
+        let mut enc: EncBox<String, XChaCha20Poly1305> = EncBox::from("FizzBuzz".to_string());
+        enc.nonce[0] ^= 1;
+        assert!(enc.try_decrypt().is_err());
+    }
+
+    //Dropping a guard re-encrypts the same pages under the next key in the chain
+    #[test]
+    fn ratchet_in_place() {
+        let mut enc: EncBox<u64, Aes256Gcm> = EncBox::new(42);
+        let (mut key, mut nonce, pages) = (enc.key, enc.nonce, enc.data.as_ptr());
+        drop(enc.decrypt());
+        ratchet_key::<Aes256Gcm>(&mut key, &mut nonce);
+        assert_eq!((enc.key, enc.nonce), (key, nonce));
+        assert_eq!(enc.data.as_ptr(), pages);
+        assert_eq!(*enc.decrypt(), 42);
+    }
+
+    #[test]
+    fn clone_and_compare() {
+        let enc: EncBox<String, XChaCha20Poly1305> = EncBox::from("FizzBuzz".to_string());
+        let mut clone = enc.clone();
+        assert_ne!(enc.key, clone.key);
+        assert!(enc == clone);
+
+        clone.decrypt().push('!');
+        assert!(enc != clone);
+        assert_eq!(*clone.decrypt(), "FizzBuzz!");
+        assert_eq!(enc.clone().decrypt(), enc.clone().decrypt());
+    }
+
+    //Clone, PartialEq and Hash only get &self, so they can run on several threads at once
+    #[test]
+    fn clone_across_threads() {
+        let enc: EncBox<[u64; 512], XChaCha20Poly1305> = EncBox::new([7; 512]);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for i in 0..1000 {
+                        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                        enc.hash(&mut hasher);
+                        if i % 100 == 0 {
+                            let mut clone = enc.clone();
+                            assert_eq!(*clone.decrypt(), [7; 512]);
+                            assert!(clone == enc);
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod enc_rwlock_tests {
+    use crate::crypto::*;
+    use std::sync::Barrier;
+
+    #[test]
+    fn shared_readers() {
+        let lock: EncRwLock<String, XChaCha20Poly1305> = EncRwLock::new("FizzBuzz".to_string());
+        {
+            let first = lock.read();
+            let second = lock.read();
+            assert_eq!(*first, *second);
+            drop(first);
+            //Still decrypted for the remaining reader
+            assert_eq!(*second, "FizzBuzz");
+        }
+        lock.write().push_str("Buzz");
+        assert_eq!(*lock.read(), "FizzBuzzBuzz");
+
+        //Every thread holds its guard at the same time
+        let barrier = Barrier::new(4);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let contents = lock.read();
+                    barrier.wait();
+                    assert_eq!(*contents, "FizzBuzzBuzz");
+                });
+            }
+        });
+
+        let mut encbox = lock.into_inner();
+        assert_eq!(encbox.state, EncBoxState::Encrypted);
+        assert_eq!(*encbox.decrypt(), "FizzBuzzBuzz");
+    }
+}
+
+#[cfg(test)]
+mod enc_vec_tests {
+    use crate::crypto::*;
+
+    //Raw view of the heap buffer, whatever state it's in
+    fn heap_bytes<T: Pod, Cipher>(enc: &EncVec<T, Cipher>) -> Vec<u8>
+    where
+        Cipher: NewAead + AeadInPlace,
+        Cipher::KeySize: IsEqual<U32, Output = True>,
+        Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+    {
+        let data = &enc.data;
+        let bytes = unsafe {
+            std::slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * size_of::<T>())
+        };
+        bytes.to_vec()
+    }
+
+    #[test]
+    fn heap_contents_encrypted() {
+        let mut enc: EncVec<u8, XChaCha20Poly1305> = EncVec::from(&b"FizzBuzz"[..]);
+        assert_ne!(heap_bytes(&enc), b"FizzBuzz");
+        {
+            let mut contents = enc.decrypt();
+            assert_eq!(&*contents, b"FizzBuzz");
+            contents.extend_from_slice(b" and then some more bytes to force a reallocation");
+            contents[0] = b'B';
+        }
+        assert_eq!(enc.len(), 57);
+        assert!(!heap_bytes(&enc).starts_with(b"BizzBuzz"));
+        assert!(enc.decrypt().starts_with(b"BizzBuzz and then"));
+
+        let mut words: EncVec<u32, Aes256Gcm> = EncVec::new(vec![1, 2, 3]);
+        words.decrypt().truncate(1);
+        assert_eq!(&*words.decrypt(), &[1]);
+    }
+
+    #[test]
+    fn string_guard() {
+        let mut enc: EncString<XChaCha20Poly1305> = EncString::from("FizzBuzz");
+        assert_ne!(heap_bytes(&enc.inner), b"FizzBuzz");
+        {
+            let mut contents = enc.decrypt();
+            contents.push_str("Buzz");
+            contents.push('!');
+            contents.make_ascii_uppercase();
+        }
+        assert_eq!(&*enc.decrypt(), "FIZZBUZZBUZZ!");
+        enc.decrypt().truncate(4);
+        assert_eq!(&*enc.decrypt(), "FIZZ");
+
+        enc.inner.tag[0] ^= 1;
+        assert_eq!(
+            enc.try_decrypt().unwrap_err(),
+            EncBoxError::AuthenticationFailed
+        );
+    }
 }
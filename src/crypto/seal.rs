@@ -0,0 +1,252 @@
+use std::fmt::{Display, Formatter};
+use typenum::Unsigned;
+
+use crate::crypto::*;
+
+//Workaround to self obfuscate (since we can't add ourselves as a dependency)
+#[allow(unused_imports)]
+use crate as r2d2;
+
+/*
+ * Sealed EncBox envelopes, for keeping secrets encrypted all the way from disk to memory
+ * The ciphertext goes out exactly as it sits in the box, alongside the box key wrapped under a
+ * key-encryption key the caller supplies
+ *
+ * Layout, integers big endian:
+ *   magic "R2D2", version, cipher id, AAD (size of T as u64)
+ *   data nonce, data tag
+ *   wrap nonce, wrapped key, wrap tag
+ *   ciphertext
+ * Everything before the wrap nonce is authenticated as the AAD of the wrapped key
+ *
+ * The ciphertext is the raw bytes of a T, so only Pod types can be sealed, any bytes that pass
+ * authentication are a valid T, and only unsealed on a platform with the same layout for T
+ */
+
+const MAGIC: &[u8; 4] = b"R2D2";
+const VERSION: u8 = 1;
+
+//Ciphers that can be named in an envelope header
+pub trait SealCipher {
+    const ID: u8;
+}
+
+impl SealCipher for XChaCha20Poly1305 {
+    const ID: u8 = 1;
+}
+
+impl SealCipher for Aes256Gcm {
+    const ID: u8 = 2;
+}
+
+//Why a sealed envelope couldn't be loaded
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum SealError {
+    //Truncated, or not an envelope at all
+    Malformed,
+    UnsupportedVersion(u8),
+    //Sealed with another cipher, holds the id found in the header
+    WrongCipher(u8),
+    //Sealed from a type of a different size than the one being loaded
+    WrongSize,
+    //Wrong key-encryption key, or the envelope was tampered with
+    AuthenticationFailed,
+}
+
+impl Display for SealError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SealError::Malformed => write!(f, "not a sealed envelope"),
+            SealError::UnsupportedVersion(version) => {
+                write!(f, "unsupported envelope version {}", version)
+            }
+            SealError::WrongCipher(id) => write!(f, "envelope sealed with cipher id {}", id),
+            SealError::WrongSize => write!(f, "envelope holds a value of a different size"),
+            SealError::AuthenticationFailed => write!(f, "envelope failed authentication"),
+        }
+    }
+}
+
+impl std::error::Error for SealError {}
+
+//Hands out the envelope piece by piece, anything short is malformed
+struct Reader<'a> {
+    sealed: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> std::result::Result<&'a [u8], SealError> {
+        if self.sealed.len() < len {
+            return Err(SealError::Malformed);
+        }
+        let (taken, rest) = self.sealed.split_at(len);
+        self.sealed = rest;
+        Ok(taken)
+    }
+}
+
+impl<T, Cipher> EncBox<T, Cipher>
+where
+    T: Pod,
+    Cipher: NewAead + AeadInPlace + SealCipher,
+    //Enforce 256 bit keys
+    Cipher::KeySize: IsEqual<U32, Output = True>,
+    Cipher::CiphertextOverhead: IsEqual<U0, Output = True>,
+{
+    //The box stays as it is, its ciphertext is copied out without ever being decrypted
+    pub fn seal(&self, kek: &Key<Cipher>) -> std::result::Result<Vec<u8>, EncBoxError> {
+        if self.state != EncBoxState::Encrypted {
+            return Err(EncBoxError::WrongState);
+        }
+
+        let mut sealed: Vec<u8> = Vec::new();
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(VERSION);
+        sealed.push(Cipher::ID);
+        sealed.extend_from_slice(&(self.aad as u64).to_be_bytes());
+        sealed.extend_from_slice(&self.nonce);
+        sealed.extend_from_slice(&self.tag);
+
+        let wrap_nonce = Self::generate_nonce();
+        let mut wrapped = self.key.to_owned();
+        let wrap_tag = Cipher::new(kek)
+            .encrypt_in_place_detached(&wrap_nonce, &sealed, &mut wrapped)
+            .unwrap();
+        sealed.extend_from_slice(&wrap_nonce);
+        sealed.extend_from_slice(&wrapped);
+        sealed.extend_from_slice(&wrap_tag);
+
+        //Other threads can be reading the same box, see ProtectedPages::read
+        self.data.read(|data| sealed.extend_from_slice(&data[..self.aad]));
+        Ok(sealed)
+    }
+
+    /*
+     * Loads an envelope straight into an encrypted box, the plaintext never exists outside its
+     * protected pages
+     * The value is decrypted and re-encrypted under a fresh key once, in place, so boxes loaded
+     * from the same envelope never ratchet through the same keys, and tampering is caught here
+     * rather than at the first decrypt
+     */
+    pub fn unseal(sealed: &[u8], kek: &Key<Cipher>) -> std::result::Result<Self, SealError> {
+        let nonce_size = <Cipher::NonceSize as Unsigned>::USIZE;
+        let tag_size = <Cipher::TagSize as Unsigned>::USIZE;
+        let mut reader = Reader { sealed };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(SealError::Malformed);
+        }
+        let version = reader.take(1)?[0];
+        if version != VERSION {
+            return Err(SealError::UnsupportedVersion(version));
+        }
+        let id = reader.take(1)?[0];
+        if id != Cipher::ID {
+            return Err(SealError::WrongCipher(id));
+        }
+        let aad = u64::from_be_bytes(reader.take(size_of::<u64>())?.try_into().unwrap());
+        if aad != size_of::<T>() as u64 {
+            return Err(SealError::WrongSize);
+        }
+        let nonce = Nonce::<Cipher>::clone_from_slice(reader.take(nonce_size)?);
+        let tag = Tag::<Cipher>::clone_from_slice(reader.take(tag_size)?);
+        let header = &sealed[..sealed.len() - reader.sealed.len()];
+
+        let wrap_nonce = Nonce::<Cipher>::clone_from_slice(reader.take(nonce_size)?);
+        let mut key = Key::<Cipher>::clone_from_slice(reader.take(size_of::<Key<Cipher>>())?);
+        let wrap_tag = Tag::<Cipher>::clone_from_slice(reader.take(tag_size)?);
+        if Cipher::new(kek)
+            .decrypt_in_place_detached(&wrap_nonce, header, &mut key, &wrap_tag)
+            .is_err()
+        {
+            return Err(SealError::AuthenticationFailed);
+        }
+
+        let ciphertext = reader.take(size_of::<T>())?;
+        if !reader.sealed.is_empty() {
+            key.zeroize();
+            return Err(SealError::Malformed);
+        }
+
+        let pages = ProtectedPages::new(size_of::<T>());
+        //SAFETY: Fresh pages big enough for the bytes of a T
+        unsafe { ptr::copy_nonoverlapping(ciphertext.as_ptr(), pages.as_ptr(), ciphertext.len()) };
+        let mut ret: EncBox<T, Cipher> = EncBox {
+            _marker: PhantomData,
+            data: pages,
+            key: key.to_owned(),
+            nonce,
+            tag,
+            aad: size_of::<T>(),
+            state: EncBoxState::Encrypted,
+        };
+        key.zeroize();
+
+        ret.decrypt_underlying()
+            .map_err(|_| SealError::AuthenticationFailed)?;
+        ret.key = Cipher::generate_key(OsRng);
+        ret.nonce = Self::generate_nonce();
+        ret.encrypt_underlying();
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod seal_tests {
+    use crate::crypto::*;
+
+    #[test]
+    fn round_trip() {
+        let kek = XChaCha20Poly1305::generate_key(OsRng);
+        let mut enc: EncBox<[u8; 32], XChaCha20Poly1305> = EncBox::new([0x42; 32]);
+        let sealed = enc.seal(&kek).unwrap();
+        assert!(!sealed.windows(32).any(|window| window == [0x42; 32]));
+        //Sealing leaves the box usable
+        assert_eq!(*enc.decrypt(), [0x42; 32]);
+
+        let mut loaded: EncBox<[u8; 32], XChaCha20Poly1305> =
+            EncBox::unseal(&sealed, &kek).unwrap();
+        assert_eq!(loaded.state, EncBoxState::Encrypted);
+        assert_eq!(*loaded.decrypt(), [0x42; 32]);
+
+        let wrong_kek = XChaCha20Poly1305::generate_key(OsRng);
+        let failed = EncBox::<[u8; 32], XChaCha20Poly1305>::unseal(&sealed, &wrong_kek);
+        assert_eq!(failed.unwrap_err(), SealError::AuthenticationFailed);
+
+        let mut tampered = sealed.to_owned();
+        *tampered.last_mut().unwrap() ^= 1;
+        let failed = EncBox::<[u8; 32], XChaCha20Poly1305>::unseal(&tampered, &kek);
+        assert_eq!(failed.unwrap_err(), SealError::AuthenticationFailed);
+
+        let failed = EncBox::<[u8; 32], XChaCha20Poly1305>::unseal(&sealed[..40], &kek);
+        assert_eq!(failed.unwrap_err(), SealError::Malformed);
+        let failed = EncBox::<u64, XChaCha20Poly1305>::unseal(&sealed, &kek);
+        assert_eq!(failed.unwrap_err(), SealError::WrongSize);
+        let failed = EncBox::<[u8; 32], Aes256Gcm>::unseal(&sealed, &kek);
+        assert_eq!(failed.unwrap_err(), SealError::WrongCipher(1));
+    }
+
+    //Sealing only gets &self, other threads can be sealing or cloning the same box meanwhile
+    #[test]
+    fn seal_across_threads() {
+        let kek = XChaCha20Poly1305::generate_key(OsRng);
+        let enc: EncBox<[u64; 512], XChaCha20Poly1305> = EncBox::new([7; 512]);
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let (enc, kek) = (&enc, &kek);
+                scope.spawn(move || {
+                    for _ in 0..200 {
+                        if thread % 2 == 0 {
+                            let sealed = enc.seal(kek).unwrap();
+                            let mut loaded: EncBox<[u64; 512], XChaCha20Poly1305> =
+                                EncBox::unseal(&sealed, kek).unwrap();
+                            assert_eq!(*loaded.decrypt(), [7; 512]);
+                        } else {
+                            assert!(enc.clone() == *enc);
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,533 @@
+use quote::*;
+use std::collections::{BTreeMap, BTreeSet};
+use syn::punctuated::Punctuated;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::*;
+
+use crate::shatter::generate_unique_ident;
+//Workaround to self obfuscate (since we can't add ourselves as a dependency)
+#[allow(unused_imports)]
+use crate as r2d2;
+
+/*
+ * In-memory encryption for values marked #[encrypt]
+ * A marked let binding becomes an EncBox, and every later statement using it gets a decrypt()
+ * guard of its own, so the value is only plaintext while that statement runs
+ * Guards are narrowed down to the statements inside blocks, loops and ifs where possible
+ * A marked named struct field becomes an EncBox too, struct literals wrap its initializer and
+ * every access turns into a decrypt() guard for the rest of the statement
+ *
+ * There are no types to go by, so fields are matched on name alone, and names marked #[encrypt]
+ * shouldn't be reused by unmarked fields anywhere in the crate
+ * Decrypting needs a mutable path to the value, and a guard can't be borrowed past its
+ * statement, both show up as compile errors in the obfuscated build
+ * Sources using the attribute only build through r2d2
+ */
+
+const ENCRYPT_ATTRIBUTE: &str = "encrypt";
+
+//Struct name to the names of its fields marked #[encrypt]
+pub type EncryptedFields = BTreeMap<String, BTreeSet<String>>;
+
+//Either the bare name or r2d2::encrypt
+fn is_encrypt_attribute(attr: &Attribute) -> bool {
+    let segments: Vec<String> = attr
+        .path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+    match segments.as_slice() {
+        [name] => name == ENCRYPT_ATTRIBUTE,
+        [krate, name] => krate == "r2d2" && name == ENCRYPT_ATTRIBUTE,
+        _ => false,
+    }
+}
+
+fn has_encrypt_attribute(attrs: &[Attribute]) -> bool {
+    attrs.iter().any(is_encrypt_attribute)
+}
+
+//Removes the attribute, returning whether it was there
+fn take_encrypt_attribute(attrs: &mut Vec<Attribute>) -> bool {
+    let marked = has_encrypt_attribute(attrs);
+    attrs.retain(|attr| !is_encrypt_attribute(attr));
+    marked
+}
+
+struct FieldCollector<'a> {
+    fields: &'a mut EncryptedFields,
+}
+
+impl<'ast, 'a> Visit<'ast> for FieldCollector<'a> {
+    fn visit_item_struct(&mut self, item: &'ast ItemStruct) {
+        for field in &item.fields {
+            if let (Some(ident), true) = (&field.ident, has_encrypt_attribute(&field.attrs)) {
+                self.fields
+                    .entry(item.ident.to_string())
+                    .or_default()
+                    .insert(ident.to_string());
+            }
+        }
+        visit::visit_item_struct(self, item);
+    }
+}
+
+//Adds the marked fields in a file, run over every file before any of them is rewritten
+pub fn collect_encrypted_fields(input: &File, fields: &mut EncryptedFields) {
+    FieldCollector { fields }.visit_file(input);
+}
+
+/*
+ * Format style macros take expressions, anything else is left alone
+ * A {name} captured inline by the format string gets an explicit name = name argument, so it
+ * can be rewritten like any other use
+ */
+fn macro_args(mac: &Macro, capture: Option<&Ident>) -> Option<Punctuated<Expr, Token![,]>> {
+    let mut args = mac
+        .parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated)
+        .ok()?;
+    let ident = match capture {
+        Some(ident) => ident,
+        None => return Some(args),
+    };
+
+    let captured = match args.first() {
+        Some(Expr::Lit(ExprLit {
+            lit: Lit::Str(format),
+            ..
+        })) => {
+            let format = format.value();
+            format.contains(&format!("{{{}}}", ident)) || format.contains(&format!("{{{}:", ident))
+        }
+        _ => false,
+    };
+    let named = args.iter().any(|arg| match arg {
+        Expr::Assign(assign) => {
+            matches!(&*assign.left, Expr::Path(path) if path.path.is_ident(ident))
+        }
+        _ => false,
+    });
+    if captured && !named {
+        args.push(parse_quote!(#ident = #ident));
+    }
+    Some(args)
+}
+
+//Named macro arguments are name = value, only the value is an expression
+fn visit_macro_arg_mut<V: VisitMut>(visitor: &mut V, arg: &mut Expr) {
+    match arg {
+        Expr::Assign(assign) => visitor.visit_expr_mut(&mut assign.right),
+        arg => visitor.visit_expr_mut(arg),
+    }
+}
+
+//Whether a local is still referred to, stopping at items since they can't see locals
+struct Finder<'a> {
+    ident: &'a Ident,
+    found: bool,
+}
+
+impl<'ast, 'a> Visit<'ast> for Finder<'a> {
+    fn visit_expr_path(&mut self, path: &'ast ExprPath) {
+        self.found |= path.qself.is_none() && path.path.is_ident(self.ident);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        if let Some(args) = macro_args(mac, Some(self.ident)) {
+            args.iter().for_each(|arg| self.visit_expr(arg));
+        }
+    }
+
+    fn visit_item(&mut self, _item: &'ast Item) {}
+}
+
+fn mentions<'ast, T, F>(node: &'ast T, ident: &Ident, visit: F) -> bool
+where
+    F: FnOnce(&mut Finder, &'ast T),
+{
+    let mut finder = Finder {
+        ident,
+        found: false,
+    };
+    visit(&mut finder, node);
+    finder.found
+}
+
+struct Binder<'a> {
+    ident: &'a Ident,
+    found: bool,
+}
+
+impl<'ast, 'a> Visit<'ast> for Binder<'a> {
+    fn visit_pat_ident(&mut self, pat: &'ast PatIdent) {
+        self.found |= pat.ident == *self.ident;
+        visit::visit_pat_ident(self, pat);
+    }
+}
+
+//Whether a pattern rebinds the name, after which uses refer to something else
+fn binds(pat: &Pat, ident: &Ident) -> bool {
+    let mut binder = Binder {
+        ident,
+        found: false,
+    };
+    binder.visit_pat(pat);
+    binder.found
+}
+
+//Points every use of a local at the guard instead
+struct Replacer<'a> {
+    ident: &'a Ident,
+    guard: &'a Ident,
+}
+
+impl<'a> VisitMut for Replacer<'a> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if let Expr::Path(path) = expr {
+            if path.qself.is_none() && path.path.is_ident(self.ident) {
+                let guard = self.guard;
+                *expr = parse_quote!((*#guard));
+                return;
+            }
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+
+    //Shorthand initializers need the field name spelled out once the value is replaced
+    fn visit_field_value_mut(&mut self, field: &mut FieldValue) {
+        if matches!(&field.member, Member::Named(member) if member == self.ident) {
+            field.colon_token.get_or_insert_with(Default::default);
+        }
+        visit_mut::visit_field_value_mut(self, field);
+    }
+
+    fn visit_macro_mut(&mut self, mac: &mut Macro) {
+        if let Some(mut args) = macro_args(mac, Some(self.ident)) {
+            args.iter_mut()
+                .for_each(|arg| visit_macro_arg_mut(self, arg));
+            mac.tokens = args.to_token_stream();
+        }
+    }
+
+    fn visit_item_mut(&mut self, _item: &mut Item) {}
+}
+
+//Wraps an expression in a block holding a decrypt() guard for the duration
+fn guarded(expr: &Expr, ident: &Ident, discard: bool) -> Expr {
+    let guard = generate_unique_ident();
+    let mut body = expr.to_owned();
+    Replacer {
+        ident,
+        guard: &guard,
+    }
+    .visit_expr_mut(&mut body);
+
+    //Statements ending in a semicolon drop their value inside the block, before the guard
+    let semi = discard.then(|| quote!(;));
+    parse_quote! {{
+        #[allow(unused_mut)]
+        let mut #guard = #ident.decrypt();
+        #body #semi
+    }}
+}
+
+fn rewrite_expr(expr: &mut Expr, ident: &Ident, discard: bool) {
+    let mentioned = |expr: &Expr| mentions(expr, ident, |finder, expr| finder.visit_expr(expr));
+    match expr {
+        Expr::Block(block) => rewrite_uses(&mut block.block.stmts, ident),
+        Expr::Unsafe(block) => rewrite_uses(&mut block.block.stmts, ident),
+        Expr::Loop(body) => rewrite_uses(&mut body.body.stmts, ident),
+        Expr::While(body) if !mentioned(&body.cond) => rewrite_uses(&mut body.body.stmts, ident),
+        Expr::ForLoop(body) if !mentioned(&body.expr) && !binds(&body.pat, ident) => {
+            rewrite_uses(&mut body.body.stmts, ident)
+        }
+        Expr::If(branch) if !mentioned(&branch.cond) => {
+            rewrite_uses(&mut branch.then_branch.stmts, ident);
+            if let Some((_, otherwise)) = &mut branch.else_branch {
+                rewrite_expr(otherwise, ident, discard);
+            }
+        }
+        _ => *expr = guarded(expr, ident, discard),
+    }
+}
+
+//Rewrites the statements following a marked binding, up to wherever the name gets rebound
+fn rewrite_uses(stmts: &mut [Stmt], ident: &Ident) {
+    for stmt in stmts {
+        if mentions(stmt, ident, |finder, stmt| finder.visit_stmt(stmt)) {
+            match stmt {
+                Stmt::Local(local) => {
+                    if let Some((_, init)) = &mut local.init {
+                        **init = guarded(init, ident, false);
+                    }
+                }
+                Stmt::Expr(expr) => rewrite_expr(expr, ident, false),
+                Stmt::Semi(expr, _) => rewrite_expr(expr, ident, true),
+                Stmt::Item(_) => {}
+            }
+        }
+        if matches!(stmt, Stmt::Local(local) if binds(&local.pat, ident)) {
+            break;
+        }
+    }
+}
+
+/*
+ * Turns a marked binding into an EncBox, returning the name it binds
+ * Bindings it can't handle are left as they are, and get a compile_error! with the message to go
+ * in front of them
+ */
+fn encrypt_local(local: &mut Local) -> std::result::Result<Ident, String> {
+    let (pat, ty) = match &local.pat {
+        Pat::Type(typed) => (&*typed.pat, Some(&typed.ty)),
+        pat => (pat, None),
+    };
+    let unsupported =
+        |reason: &str| format!("#[encrypt] {}: let {}", reason, pat.to_token_stream());
+    let ident = match pat {
+        Pat::Ident(binding) if binding.by_ref.is_none() && binding.subpat.is_none() => {
+            binding.ident.to_owned()
+        }
+        _ => return Err(unsupported("only supports bindings of a single name")),
+    };
+    let init = match &local.init {
+        Some((_, init)) => init,
+        None => return Err(unsupported("needs an initializer")),
+    };
+    let ty = match ty {
+        Some(ty) => quote!(#ty),
+        None => quote!(_),
+    };
+    let attrs = &local.attrs;
+
+    let stmt: Stmt = parse_quote! {
+        #(#attrs)*
+        #[allow(unused_mut)]
+        let mut #ident: r2d2::crypto::EncBox<#ty, r2d2::crypto::XChaCha20Poly1305> =
+            r2d2::crypto::EncBox::new(#init);
+    };
+    match stmt {
+        Stmt::Local(encrypted) => *local = encrypted,
+        _ => unreachable!(),
+    }
+    Ok(ident)
+}
+
+struct EncryptAttributes<'a> {
+    fields: &'a EncryptedFields,
+    //Every marked field name, whatever struct it's in
+    field_names: BTreeSet<String>,
+    //Name of the type whose impl we're in, what Self refers to
+    self_type: Option<String>,
+}
+
+impl<'a> VisitMut for EncryptAttributes<'a> {
+    fn visit_item_struct_mut(&mut self, item: &mut ItemStruct) {
+        for field in item.fields.iter_mut() {
+            if field.ident.is_some() && take_encrypt_attribute(&mut field.attrs) {
+                let ty = &field.ty;
+                field.ty = parse_quote!(r2d2::crypto::EncBox<#ty, r2d2::crypto::XChaCha20Poly1305>);
+            }
+        }
+        visit_mut::visit_item_struct_mut(self, item);
+    }
+
+    fn visit_item_impl_mut(&mut self, item: &mut ItemImpl) {
+        let self_type = match &*item.self_ty {
+            Type::Path(path) => path.path.segments.last().map(|s| s.ident.to_string()),
+            _ => None,
+        };
+        let outer = std::mem::replace(&mut self.self_type, self_type);
+        visit_mut::visit_item_impl_mut(self, item);
+        self.self_type = outer;
+    }
+
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        let mut i = 0;
+        while i < block.stmts.len() {
+            let encrypted = match &mut block.stmts[i] {
+                Stmt::Local(local) if has_encrypt_attribute(&local.attrs) => {
+                    take_encrypt_attribute(&mut local.attrs);
+                    encrypt_local(local)
+                }
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            match encrypted {
+                Ok(ident) => rewrite_uses(&mut block.stmts[i + 1..], &ident),
+                Err(message) => {
+                    let error: Stmt = parse_quote!(compile_error!(#message););
+                    block.stmts.insert(i, error);
+                    i += 1;
+                }
+            }
+            i += 1;
+        }
+        visit_mut::visit_block_mut(self, block);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        visit_mut::visit_expr_mut(self, expr);
+        match expr {
+            Expr::Struct(literal) => {
+                let name = match literal.path.segments.last() {
+                    Some(segment) if segment.ident == "Self" => self.self_type.to_owned(),
+                    Some(segment) => Some(segment.ident.to_string()),
+                    None => None,
+                };
+                let marked = match name.and_then(|name| self.fields.get(&name)) {
+                    Some(marked) => marked,
+                    None => return,
+                };
+                for field in literal.fields.iter_mut() {
+                    if let Member::Named(member) = &field.member {
+                        if marked.contains(&member.to_string()) {
+                            field.colon_token.get_or_insert_with(Default::default);
+                            let value = &field.expr;
+                            field.expr = parse_quote!(r2d2::crypto::EncBox::new(#value));
+                        }
+                    }
+                }
+            }
+            Expr::Field(access) => {
+                if let Member::Named(member) = &access.member {
+                    if self.field_names.contains(&member.to_string()) {
+                        *expr = parse_quote!((*#access.decrypt()));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    //Only touches the tokens when a field access was actually rewritten
+    fn visit_macro_mut(&mut self, mac: &mut Macro) {
+        if let Some(mut args) = macro_args(mac, None) {
+            let before = args.to_token_stream().to_string();
+            args.iter_mut()
+                .for_each(|arg| visit_macro_arg_mut(self, arg));
+            if args.to_token_stream().to_string() != before {
+                mac.tokens = args.to_token_stream();
+            }
+        }
+    }
+}
+
+//fields has to include the marked fields of every file in the crate, see collect_encrypted_fields
+pub fn encrypt_marked(input: &mut File, fields: &EncryptedFields) {
+    let mut state = EncryptAttributes {
+        fields,
+        field_names: fields.values().flatten().cloned().collect(),
+        self_type: None,
+    };
+    state.visit_file_mut(input);
+}
+
+#[cfg(test)]
+mod encrypt_attribute_tests {
+    use crate::encattr::*;
+
+    //Whitespace stripped, prettyplease wraps the long generated lines wherever it likes
+    fn apply(source: &str) -> String {
+        let mut file = syn::parse_file(source).unwrap();
+        let mut fields = EncryptedFields::new();
+        collect_encrypted_fields(&file, &mut fields);
+        encrypt_marked(&mut file, &fields);
+        prettyplease::unparse(&file).split_whitespace().collect()
+    }
+
+    #[test]
+    fn locals() {
+        let output = apply(
+            r#"
+            fn main() {
+                #[encrypt]
+                let secret: String = load();
+                let len = secret.len();
+                for i in 0..len {
+                    println!("{} {secret}", i);
+                }
+                secret.push('!');
+                let secret = 5;
+                println!("{}", secret);
+            }
+            "#,
+        );
+        assert!(!output.contains("#[encrypt]"));
+        assert!(output.contains(
+            "letmutsecret:r2d2::crypto::EncBox<String,r2d2::crypto::XChaCha20Poly1305>=\
+             r2d2::crypto::EncBox::new(load(),);"
+        ));
+        //A guard for each statement using it, inside the loop rather than around it
+        assert_eq!(output.matches("=secret.decrypt();").count(), 3);
+        assert!(output.contains("foriin0..len{{"));
+        assert!(output.contains("i,secret=(*var_"));
+        //Shadowed from here on
+        assert!(output.ends_with("letsecret=5;println!(\"{}\",secret);}"));
+    }
+
+    #[test]
+    fn fields() {
+        let output = apply(
+            r#"
+            struct Config {
+                #[encrypt]
+                token: String,
+                name: String,
+            }
+            impl Config {
+                fn new(token: String) -> Self {
+                    Self { token, name: String::new() }
+                }
+                fn show(&mut self) {
+                    println!("{} {}", self.name, self.token);
+                    self.token.clear();
+                }
+            }
+            "#,
+        );
+        assert!(
+            output.contains("token:r2d2::crypto::EncBox<String,r2d2::crypto::XChaCha20Poly1305>,")
+        );
+        assert!(output.contains("name:String,"));
+        assert!(output.contains("token:r2d2::crypto::EncBox::new(token),"));
+        assert!(output.contains("self.name,(*self.token.decrypt())"));
+        assert!(output.contains("(*self.token.decrypt()).clear();"));
+    }
+
+    #[test]
+    fn paths_and_errors() {
+        let output = apply(
+            r#"
+            struct Session {
+                #[r2d2::encrypt]
+                key: Vec<u8>,
+            }
+            fn main() {
+                #[r2d2::encrypt]
+                let (user, pass) = login();
+                #[encrypt]
+                let token;
+                token = 5;
+            }
+            "#,
+        );
+        assert!(!output.contains("#[r2d2::encrypt]"));
+        assert!(!output.contains("#[encrypt]let"));
+        assert!(
+            output.contains("key:r2d2::crypto::EncBox<Vec<u8>,r2d2::crypto::XChaCha20Poly1305>,")
+        );
+        //Unsupported bindings stay as they were, right after an error pointing at them
+        assert!(output.contains(
+            "compile_error!(\"#[encrypt]onlysupportsbindingsofasinglename:let(user,pass)\");\
+             let(user,pass)=login();"
+        ));
+        assert!(output.contains(
+            "compile_error!(\"#[encrypt]needsaninitializer:lettoken\");lettoken;token=5;"
+        ));
+    }
+}
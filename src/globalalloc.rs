@@ -0,0 +1,79 @@
+use quote::*;
+use syn::*;
+
+use crate::shatter::generate_unique_ident;
+//Workaround to self obfuscate (since we can't add ourselves as a dependency)
+#[allow(unused_imports)]
+use crate as r2d2;
+
+/*
+ * Installs r2d2::alloc::ZeroizingAllocator as the global allocator of binary crates
+ * Same as the panic hook, only meant for the roots of binary targets, and only ones with a
+ * fn main, build scripts can't allocate through r2d2
+ * Crates that already pick their own allocator keep it, a second #[global_allocator] won't build
+ */
+pub fn install_zeroizing_allocator(input: &mut File) {
+    let is_binary = input
+        .items
+        .iter()
+        .any(|item| matches!(item, Item::Fn(function) if function.sig.ident == "main"));
+    let has_allocator = input.items.iter().any(|item| match item {
+        Item::Static(item) => item
+            .attrs
+            .iter()
+            .any(|attr| attr.path.is_ident("global_allocator")),
+        _ => false,
+    });
+    if !is_binary || has_allocator {
+        return;
+    }
+
+    let ident = generate_unique_ident();
+    let tokens = quote! {
+        #[global_allocator]
+        #[allow(non_upper_case_globals)]
+        static #ident: r2d2::alloc::ZeroizingAllocator = r2d2::alloc::ZeroizingAllocator::new();
+    };
+    input.items.push(syn::parse2::<Item>(tokens).unwrap());
+}
+
+#[cfg(test)]
+mod global_alloc_tests {
+    use crate::globalalloc::*;
+    use std::path::Path;
+
+    #[test]
+    fn binaries_only() {
+        let mut file = syn::parse_file("fn main() {}").unwrap();
+        install_zeroizing_allocator(&mut file);
+        let output = prettyplease::unparse(&file);
+        assert!(output.contains("#[global_allocator]"));
+        assert!(output.contains("r2d2::alloc::ZeroizingAllocator::new()"));
+
+        let mut file = syn::parse_file("pub fn helper() {}").unwrap();
+        install_zeroizing_allocator(&mut file);
+        assert_eq!(file.items.len(), 1);
+
+        let mut file = syn::parse_file(
+            "#[global_allocator] static A: std::alloc::System = std::alloc::System; fn main() {}",
+        )
+        .unwrap();
+        install_zeroizing_allocator(&mut file);
+        assert_eq!(file.items.len(), 2);
+    }
+
+    #[test]
+    fn skips_build_scripts() {
+        let mut config = crate::ObfuscateConfig {
+            zeroizing_allocator: true,
+            ..Default::default()
+        };
+        config.binary_roots.insert("/crate/src/main.rs".into());
+        let source = String::from("fn main() {}");
+
+        let build = crate::obfuscate(&source, Some(Path::new("/crate/build.rs")), &config);
+        assert!(!build.source.contains("#[global_allocator]"));
+        let main = crate::obfuscate(&source, Some(Path::new("/crate/src/main.rs")), &config);
+        assert!(main.source.contains("#[global_allocator]"));
+    }
+}
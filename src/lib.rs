@@ -1,10 +1,13 @@
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
 use cargo_metadata::{Message, Metadata, MetadataCommand};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::env;
 use std::fs::{self, DirBuilder, OpenOptions};
 use std::io::{self, BufReader, ErrorKind};
 use std::process::{Child, Command, ExitStatus, Output, Stdio};
+#[cfg(windows)]
 use std::os::windows::process::ExitStatusExt;
 use std::path;
 use walkdir::WalkDir;
@@ -23,14 +26,38 @@ pub use windows;
 
 //Grab our submodules
 pub mod crypto;
+pub mod strings;
+pub mod panics;
+pub mod alloc;
+pub mod stack;
 mod shuffle;
 mod strencrypt;
 mod shatter;
 mod parse;
+mod scan;
+mod remap;
+mod panichook;
+mod decoy;
+mod protect;
+mod encattr;
+mod globalalloc;
+mod scrubstack;
 //Import symbols from those submodules
 use crate::shuffle::*;
 use crate::strencrypt::*;
 use crate::shatter::*;
+use crate::panichook::install_panic_hook;
+use crate::encattr::{collect_encrypted_fields, encrypt_marked};
+use crate::globalalloc::install_zeroizing_allocator;
+use crate::scrubstack::scrub_stacks;
+use crate::crypto::HashAlgorithm;
+pub use crate::decoy::{DecoyConfig, DEFAULT_DECOYS};
+pub use crate::panichook::PanicMode;
+pub use crate::encattr::EncryptedFields;
+pub use crate::scan::{Leak, NeedleKind};
+pub use crate::strencrypt::{
+    DecryptFailure, LiteralAudit, SkipReason, SkippedLiteral, StrEncConfig, StringSchemeKind,
+};
 
 //Workaround to self obfuscate (since we can't add ourselves as a dependency)
 #[allow(unused_imports)]
@@ -83,20 +110,77 @@ use crate as r2d2;
  * It's called ptr_metadata, something to keep an eye out for
  */
 
-pub fn obfuscate(input: &String) -> (String, Shatter) {
+//Options for the individual obfuscation passes
+#[derive(Debug, Clone, Default)]
+pub struct ObfuscateConfig {
+    pub strings: StrEncConfig,
+    pub panics: PanicMode,
+    pub decoys: DecoyConfig,
+    //Hash used by the integrity checks shatter injects
+    pub integrity_hash: HashAlgorithm,
+    //Fields marked #[encrypt] in other files of the crate, filled in by obfuscate_dir
+    pub encrypted_fields: EncryptedFields,
+    /*
+     * Root files of bin and example targets, filled in by obfuscate_dir
+     * Build scripts and libraries can have a fn main of their own, so only these get the panic hook
+     * and the zeroizing allocator
+     */
+    pub binary_roots: BTreeSet<path::PathBuf>,
+    //Install r2d2::alloc::ZeroizingAllocator as the global allocator of binary crates
+    pub zeroizing_allocator: bool,
+    //Stack scrubbed by #[r2d2::scrub_stack] without a count, None for DEFAULT_SCRUB_BYTES
+    pub scrub_stack_bytes: Option<usize>,
+}
+
+pub struct ObfuscatedFile {
+    pub source: String,
+    pub shatter: Shatter,
+    //String literals that had to be left as plaintext
+    pub skipped_strings: Vec<SkippedLiteral>,
+}
+
+//source_path is where the file originally lived, used to resolve paths relative to it
+pub fn obfuscate(
+    input: &String,
+    source_path: Option<&path::Path>,
+    config: &ObfuscateConfig,
+) -> ObfuscatedFile {
     let mut input2 = syn::parse_file(&input).unwrap();
 
+    //Runs first, the passes after it treat the guards like any other code
+    let mut encrypted_fields = config.encrypted_fields.to_owned();
+    collect_encrypted_fields(&input2, &mut encrypted_fields);
+    encrypt_marked(&mut input2, &encrypted_fields);
+    scrub_stacks(
+        &mut input2,
+        config
+            .scrub_stack_bytes
+            .unwrap_or(stack::DEFAULT_SCRUB_BYTES),
+    );
+
     //eprintln!("INPUT: {:#?}", input2);
     //eprintln!("INFORMAT: {}", prettyplease::unparse(&input2));
 
     shuffle(&mut input2);
-    encrypt_strings(&mut input2);
-    let shatter = shatter(&mut input2);
+    let is_binary = source_path.map_or(false, |path| config.binary_roots.contains(path));
+    //After shuffling, the hook has to stay the first statement of main
+    if is_binary {
+        install_panic_hook(&mut input2, config.panics);
+    }
+    if config.zeroizing_allocator && is_binary {
+        install_zeroizing_allocator(&mut input2);
+    }
+    let skipped_strings = encrypt_strings(&mut input2, source_path, &config.strings);
+    let shatter = shatter(&mut input2, &config.decoys, config.integrity_hash);
 
     //eprintln!("OUTPUT: {:#?}", input2);
     //eprintln!("OUTFORMAT: {}", prettyplease::unparse(&input2));
 
-    (prettyplease::unparse(&input2), shatter)
+    ObfuscatedFile {
+        source: prettyplease::unparse(&input2),
+        shatter,
+        skipped_strings,
+    }
 }
 
 pub fn generate_temp_folder_name(name: Option<&str>) -> Utf8PathBuf {
@@ -105,24 +189,245 @@ pub fn generate_temp_folder_name(name: Option<&str>) -> Utf8PathBuf {
     output
 }
 
-pub fn obfuscate_dir(dir: &Utf8PathBuf) -> io::Result<Vec<Shatter>> {
+//src_dir is the original directory dir was copied from
+pub fn obfuscate_dir(
+    dir: &Utf8PathBuf,
+    src_dir: &Utf8PathBuf,
+    config: &ObfuscateConfig,
+) -> io::Result<Vec<Shatter>> {
     //WalkDir filter_entry will prevent the directory from being touched, so have to filter
     //manually
 
     let mut shatter_states: Vec<Shatter> = Vec::new();
 
+    //Marked fields can be used anywhere in the crate, so gather them before rewriting anything
+    let mut config = config.to_owned();
+    let metadata = MetadataCommand::new()
+        .current_dir(src_dir)
+        .no_deps()
+        .exec()
+        .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+    config.binary_roots = metadata
+        .packages
+        .iter()
+        .flat_map(|package| &package.targets)
+        .filter(|target| {
+            target
+                .kind
+                .iter()
+                .any(|kind| kind == "bin" || kind == "example")
+        })
+        .map(|target| target.src_path.to_owned().into_std_path_buf())
+        .collect();
+    for file_path in workspace_source_files(dir)? {
+        if let Ok(parsed) = syn::parse_file(&fs::read_to_string(&file_path)?) {
+            collect_encrypted_fields(&parsed, &mut config.encrypted_fields);
+        }
+    }
+    let config = &config;
+
     for file in WalkDir::new(dir) {
         let file_path = file?.into_path();
         if file_path.to_str().unwrap_or_default().ends_with(".rs") {
             let contents = fs::read_to_string(&file_path)?;
-            let (obfuscated, shatter_state) = obfuscate(&contents);
-            shatter_states.push(shatter_state);
-            fs::write(&file_path, &obfuscated)?;
+            let source_path = file_path
+                .strip_prefix(dir)
+                .ok()
+                .map(|relative| src_dir.as_std_path().join(relative));
+            let obfuscated = obfuscate(&contents, source_path.as_deref(), config);
+            for skipped in &obfuscated.skipped_strings {
+                eprintln!(
+                    "{}:{}:{}: String literal left unencrypted ({})",
+                    file_path.display(),
+                    skipped.line,
+                    skipped.column + 1,
+                    skipped.reason
+                );
+            }
+            shatter_states.push(obfuscated.shatter);
+            fs::write(&file_path, &obfuscated.source)?;
         }
     }
     Ok(shatter_states)
 }
 
+//Every Rust source file under dir, skipping what copy_dir skips since none of it gets obfuscated
+fn workspace_source_files(dir: &Utf8PathBuf) -> io::Result<Vec<path::PathBuf>> {
+    let mut files: Vec<path::PathBuf> = Vec::new();
+
+    let entries = WalkDir::new(dir).into_iter().filter_entry(|e| {
+        let name = e.file_name().to_str().unwrap_or_default();
+        !((e.depth() > 0 && name.starts_with(".")) || (e.depth() == 1 && name == "target"))
+    });
+    for entry in entries {
+        let file_path = entry?.into_path();
+        if file_path.to_str().unwrap_or_default().ends_with(".rs") {
+            files.push(file_path);
+        }
+    }
+    Ok(files)
+}
+
+//A string literal found by audit_strings_dir
+#[derive(Debug, Clone, Serialize)]
+pub struct StringAuditEntry {
+    pub file: String,
+    pub line: usize,
+    //1-based, like compiler diagnostics
+    pub column: usize,
+    pub encrypted: bool,
+    pub reason: Option<SkipReason>,
+}
+
+//Runs the string encryption pass in analysis mode over a source tree, without touching it
+pub fn audit_strings_dir(
+    dir: &Utf8PathBuf,
+    config: &ObfuscateConfig,
+) -> io::Result<Vec<StringAuditEntry>> {
+    let mut entries: Vec<StringAuditEntry> = Vec::new();
+
+    for file_path in workspace_source_files(dir)? {
+        let contents = fs::read_to_string(&file_path)?;
+        let parsed = syn::parse_file(&contents)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let file_name = file_path
+            .strip_prefix(dir)
+            .unwrap_or(&file_path)
+            .display()
+            .to_string();
+
+        for literal in audit_strings(&parsed, Some(&file_path), &config.strings) {
+            entries.push(StringAuditEntry {
+                file: file_name.to_owned(),
+                line: literal.line,
+                column: literal.column + 1,
+                encrypted: literal.reason.is_none(),
+                reason: literal.reason,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/*
+ * The environment variable and value that make cargo remap source paths under a build directory
+ * target_dir is the one cargo builds into, the names stay the same as long as it's kept
+ */
+pub fn path_scrub_env(
+    build_root: &Utf8Path,
+    target_dir: &Utf8Path,
+) -> io::Result<(&'static str, String)> {
+    let remaps = remap::path_remaps(build_root, target_dir)?;
+    Ok((remap::RUSTFLAGS_VAR, remap::encoded_rustflags(&remaps)))
+}
+
+//Everything r2d2 planted in a build that isn't obvious from the source
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ObfuscationReport {
+    //Fake plaintext strings, secret scanners should ignore these
+    pub decoys: Vec<String>,
+}
+
+const REPORT_FILE_NAME: &str = "r2d2-report.json";
+
+impl ObfuscationReport {
+    pub fn new(shatter_states: &[Shatter]) -> Self {
+        let decoys: BTreeSet<&String> = shatter_states
+            .iter()
+            .flat_map(|shatter| shatter.decoys())
+            .collect();
+        ObfuscationReport {
+            decoys: decoys.into_iter().cloned().collect(),
+        }
+    }
+
+    //The report lives in the target directory, next to the binaries it describes
+    pub fn path(target_dir: &Utf8Path) -> Utf8PathBuf {
+        target_dir.join(REPORT_FILE_NAME)
+    }
+
+    pub fn write(&self, target_dir: &Utf8Path) -> io::Result<()> {
+        DirBuilder::new().recursive(true).create(target_dir)?;
+        let contents = serde_json::to_string_pretty(self).unwrap();
+        fs::write(Self::path(target_dir), contents)
+    }
+
+    //None if nothing was obfuscated into this target directory yet
+    pub fn read(target_dir: &Utf8Path) -> io::Result<Option<Self>> {
+        let contents = match fs::read_to_string(Self::path(target_dir)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+/*
+ * Checks a compiled binary for plaintext left over from the workspace it was built from
+ * String literals, source paths, crate, module and type names are all fair game
+ * Decoys listed in the obfuscation report are planted on purpose, so they never count
+ */
+pub fn scan_workspace_binary(binary: &Utf8Path, workspace_root: &Utf8PathBuf) -> io::Result<Vec<Leak>> {
+    let metadata = MetadataCommand::new()
+        .current_dir(workspace_root)
+        .no_deps()
+        .exec()
+        .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+
+    let mut needles = scan::NeedleSet::default();
+    needles.add(
+        NeedleKind::SourcePath,
+        workspace_root.as_str().as_bytes(),
+        String::from("workspace root"),
+    );
+
+    //Crate names as they appear in paths, alongside the directory each crate's sources live in
+    let crates: Vec<(String, Utf8PathBuf)> = metadata
+        .packages
+        .iter()
+        .map(|package| {
+            let name = package.name.replace('-', "_");
+            let dir = package.manifest_path.parent().unwrap().to_owned();
+            (name, dir)
+        })
+        .collect();
+    for (name, dir) in &crates {
+        needles.add(NeedleKind::CrateName, name.as_bytes(), dir.to_string());
+    }
+
+    for file_path in workspace_source_files(workspace_root)? {
+        let contents = fs::read_to_string(&file_path)?;
+        let parsed = match syn::parse_file(&contents) {
+            Ok(parsed) => parsed,
+            //Unparseable files would've failed the build anyway
+            Err(_) => continue,
+        };
+
+        //Nested workspace members own their files, so pick the deepest crate directory
+        let crate_name = crates
+            .iter()
+            .filter(|(_, dir)| file_path.starts_with(dir))
+            .max_by_key(|(_, dir)| dir.as_str().len())
+            .map(|(name, _)| name.as_str());
+
+        //Paths are handed to rustc relative to the workspace root
+        let relative = file_path.strip_prefix(workspace_root).unwrap_or(&file_path);
+        needles.add_source(relative, &parsed, crate_name);
+    }
+
+    let contents = fs::read(binary)?;
+    let mut leaks = scan::scan_binary(&contents, &needles.into_needles())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+
+    if let Some(report) = ObfuscationReport::read(&metadata.target_directory)? {
+        leaks.retain(|leak| !report.decoys.contains(&leak.text));
+    }
+    Ok(leaks)
+}
+
 //TODO: Only copy differences with hashes/mtime checks
 //TODO: This needs to be optimized and cleaned up
 //TODO: Fix the error checking
@@ -198,6 +503,11 @@ pub struct R2D2Config<'a> {
     pub need_obfuscate: bool,
     pub obfuscate_dir: Option<&'a str>,
     pub stream_output: bool,
+    pub obfuscate_config: ObfuscateConfig,
+    //Check the built executables for plaintext leaks from the source
+    pub scan_binaries: bool,
+    //Remap source paths to random names in panic locations and debug info
+    pub scrub_paths: bool,
 }
 
 pub fn build(config: &R2D2Config) -> io::Result<ExitStatus> {
@@ -213,18 +523,29 @@ pub fn build(config: &R2D2Config) -> io::Result<ExitStatus> {
 
     copy_dir(&src.workspace_root, &dest)?;
 
+    let mut src_dir = src.workspace_root.to_owned();
+    let build_root = dest.to_owned();
+
     if let Some(partial) = config.obfuscate_dir {
         let mut true_dest_str = String::from(dest.as_str());
         true_dest_str.push(path::MAIN_SEPARATOR);
         true_dest_str.push_str(partial);
 
         dest = Utf8PathBuf::from(true_dest_str);
+        src_dir.push(partial);
     }
 
     let mut shatter_states: Vec<Shatter> = Vec::new();
 
     if config.need_obfuscate {
-        shatter_states = obfuscate_dir(&dest)?;
+        shatter_states = obfuscate_dir(&dest, &src_dir, &config.obfuscate_config)?;
+        ObfuscationReport::new(&shatter_states).write(&src.target_dir)?;
+    }
+
+    //Every cargo invocation needs the same flags, or cargo run would rebuild without them
+    let mut rustflags_env: Vec<(&str, String)> = Vec::new();
+    if config.scrub_paths {
+        rustflags_env.push(path_scrub_env(&build_root, &src.target_dir)?);
     }
 
     let mut command: Child;
@@ -238,6 +559,7 @@ pub fn build(config: &R2D2Config) -> io::Result<ExitStatus> {
             .arg(&src.target_dir)
             .args(cargo_args)
             .current_dir(&dest)
+            .envs(rustflags_env.to_owned())
             .stdout(Stdio::piped())
             .spawn().unwrap();
     } else {
@@ -247,6 +569,7 @@ pub fn build(config: &R2D2Config) -> io::Result<ExitStatus> {
             .arg("--target-dir")
             .arg(&src.target_dir)
             .current_dir(&dest)
+            .envs(rustflags_env.to_owned())
             .stdout(Stdio::piped())
             .spawn().unwrap();
     }
@@ -281,9 +604,17 @@ pub fn build(config: &R2D2Config) -> io::Result<ExitStatus> {
     eprintln!("Post command exit {status:#?}");
 
     //Post compilation
-    for binary in executables {
+    for binary in &executables {
         for shatter in &shatter_states {
-            shatter.post_compilation(&binary);
+            shatter.post_compilation(binary);
+        }
+    }
+
+    if config.scan_binaries {
+        for binary in &executables {
+            for leak in scan_workspace_binary(binary, &src.workspace_root)? {
+                eprintln!("{}: {}", binary, leak);
+            }
         }
     }
 
@@ -296,6 +627,7 @@ pub fn build(config: &R2D2Config) -> io::Result<ExitStatus> {
                 .arg(&src.target_dir)
                 .args(cargo_args)
                 .current_dir(&dest)
+                .envs(rustflags_env.to_owned())
                 .output().unwrap();
         } else {
             output = Command::new("cargo")
@@ -303,6 +635,7 @@ pub fn build(config: &R2D2Config) -> io::Result<ExitStatus> {
                 .arg("--target-dir")
                 .arg(&src.target_dir)
                 .current_dir(&dest)
+                .envs(rustflags_env.to_owned())
                 .output().unwrap();
         }
         return Ok(output.status);
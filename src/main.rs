@@ -1,5 +1,6 @@
-use clap::{app_from_crate, arg, App, AppSettings};
+use clap::{app_from_crate, arg, App, AppSettings, ArgMatches};
 use r2d2::*;
+use r2d2::crypto::HashAlgorithm;
 use std::env;
 use std::fs;
 use std::fs::DirBuilder;
@@ -71,12 +72,155 @@ fn main() -> io::Result<()> {
                         .required(false),
                 ),
         )
+        .subcommand(
+            App::new("audit-strings")
+                .about("Report which string literals in the workspace would ship as plaintext")
+                .arg(arg!(--json "Print the report as JSON").required(false))
+                .arg(
+                    arg!(--budget <COUNT> "Fail if more than COUNT literals are left unencrypted")
+                        .validator(|count| count.parse::<usize>())
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            App::new("scan")
+                .about("Look for plaintext leaks from the workspace in a compiled binary")
+                .arg(arg!(<binary> "Path to the ELF binary to scan"))
+                .arg(arg!(--json "Print the leaks as JSON").required(false)),
+        )
+        .subcommand(
+            App::new("decode-panic")
+                .about("Decrypt a panic report printed by a binary built with --panics encrypt")
+                .arg(
+                    arg!(--key <HEX> "Report key the binary was built with")
+                        .validator(|key| r2d2::panics::parse_key(key).ok_or("Expected 64 hex digits")),
+                )
+                .arg(arg!(<report> "The report line, with or without its prefix")),
+        )
         .arg(arg!(-p --plain "Disable obfuscation of the workspace").required(false))
+        .arg(
+            arg!(--"keep-paths" "Leave source paths in panic locations and debug info. Scrubbing them sets CARGO_ENCODED_RUSTFLAGS, which overrides build.rustflags from cargo config files")
+                .required(false),
+        )
+        .arg(
+            arg!(--"string-cache" <POLICY> "Cache decrypted string literals (forever, thread, every:N)")
+                .validator(|policy| policy.parse::<r2d2::strings::CachePolicy>())
+                .required(false),
+        )
+        .arg(
+            arg!(--"string-scheme" <SCHEME> "Default string encryption scheme (xchacha20poly1305, aes-gcm, chacha20, xor)")
+                .validator(|scheme| scheme.parse::<StringSchemeKind>())
+                .required(false),
+        )
+        .arg(
+            arg!(--"stack-strings" <MAX_LEN> "Build string literals up to MAX_LEN bytes on the stack instead of decrypting them")
+                .validator(|len| len.parse::<usize>())
+                .required(false),
+        )
+        .arg(
+            arg!(--"on-decrypt-failure" <POLICY> "Response to a string literal failing to decrypt (panic, garbage, abort, shatter)")
+                .validator(|policy| policy.parse::<DecryptFailure>())
+                .required(false),
+        )
+        .arg(
+            arg!(--panics <MODE> "Replace the panic hook in release builds (keep, suppress, encrypt)")
+                .possible_values(["keep", "suppress", "encrypt"])
+                .required(false),
+        )
+        .arg(
+            arg!(--"panic-key" <HEX> "Report key for --panics encrypt, needed to read the reports")
+                .validator(|key| r2d2::panics::parse_key(key).ok_or("Expected 64 hex digits"))
+                .required(false)
+                .required_if_eq("panics", "encrypt"),
+        )
+        .arg(arg!(--"panic-messages" "Encrypt panic!, assert and expect() messages").required(false))
+        .arg(arg!(--decoys "Plant fake plaintext strings in unused statics and dead branches").required(false))
+        .arg(
+            arg!(--"decoy-words" <FILE> "Decoy strings to plant, one per line, instead of the built in list")
+                .required(false)
+                .requires("decoys"),
+        )
+        .arg(
+            arg!(--"scrub-stack-bytes" <BYTES> "Stack scrubbed by #[r2d2::scrub_stack] functions that don't give an amount")
+                .validator(|bytes| bytes.parse::<usize>())
+                .required(false),
+        )
+        .arg(arg!(--"zeroize-heap" "Wipe heap memory when it's freed or shrunk, in binary crates").required(false))
+        .arg(
+            arg!(--"integrity-hash" <ALGORITHM> "Hash used by injected integrity checks, blake2s variants are faster on 32 bit targets")
+                .possible_values([
+                    "blake2b512",
+                    "blake2b256",
+                    "blake2s256",
+                    "blake2b-mac512",
+                    "blake2s-mac256",
+                    "hmac-blake2b512",
+                    "hmac-blake2s256",
+                ])
+                .required(false),
+        )
         .get_matches();
 
+    let mut obfuscate_config = ObfuscateConfig::default();
+    //Validator guarantees the policy parses
+    obfuscate_config.strings.cache_policy = matches
+        .value_of("string-cache")
+        .map(|policy| policy.parse().unwrap());
+    if let Some(scheme) = matches.value_of("string-scheme") {
+        obfuscate_config.strings.scheme = scheme.parse().unwrap();
+    }
+    if let Some(len) = matches.value_of("stack-strings") {
+        obfuscate_config.strings.stack_threshold = len.parse().unwrap();
+    }
+    if let Some(policy) = matches.value_of("on-decrypt-failure") {
+        obfuscate_config.strings.on_failure = policy.parse().unwrap();
+    }
+    obfuscate_config.strings.panic_messages = matches.is_present("panic-messages");
+    obfuscate_config.decoys.enabled = matches.is_present("decoys");
+    if let Some(file) = matches.value_of("decoy-words") {
+        obfuscate_config.decoys.words = fs::read_to_string(file)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+    obfuscate_config.zeroizing_allocator = matches.is_present("zeroize-heap");
+    //Validator guarantees the count parses
+    obfuscate_config.scrub_stack_bytes = matches
+        .value_of("scrub-stack-bytes")
+        .map(|bytes| bytes.parse().unwrap());
+    obfuscate_config.integrity_hash = match matches.value_of("integrity-hash") {
+        Some("blake2b256") => HashAlgorithm::Blake2b256,
+        Some("blake2s256") => HashAlgorithm::Blake2s256,
+        Some("blake2b-mac512") => HashAlgorithm::Blake2bMac512,
+        Some("blake2s-mac256") => HashAlgorithm::Blake2sMac256,
+        Some("hmac-blake2b512") => HashAlgorithm::HmacBlake2b512,
+        Some("hmac-blake2s256") => HashAlgorithm::HmacBlake2s256,
+        _ => HashAlgorithm::Blake2b512,
+    };
+    obfuscate_config.panics = match matches.value_of("panics") {
+        Some("suppress") => PanicMode::Suppress,
+        Some("encrypt") => {
+            //Required with encrypt, and the validator guarantees the key parses
+            let key = matches.value_of("panic-key").unwrap();
+            PanicMode::Encrypt(r2d2::panics::parse_key(key).unwrap())
+        }
+        _ => PanicMode::Keep,
+    };
+
     let cargo_args: Vec<&str>;
 
     match matches.subcommand() {
+        Some(("audit-strings", sub_matches)) => {
+            return audit_strings(sub_matches, &obfuscate_config);
+        }
+        Some(("decode-panic", sub_matches)) => {
+            return decode_panic(sub_matches);
+        }
+        Some(("scan", sub_matches)) => {
+            return scan(sub_matches);
+        }
         Some(("build", sub_matches)) => {
             cargo_args = sub_matches
                 .values_of("args")
@@ -135,8 +279,13 @@ fn main() -> io::Result<()> {
 
     copy_dir(&src.workspace_root, &dest)?;
 
+    let mut rustflags_env: Vec<(&str, String)> = Vec::new();
     if need_obfuscate {
-        obfuscate_dir(&dest)?;
+        let shatter_states = obfuscate_dir(&dest, &src.workspace_root, &obfuscate_config)?;
+        ObfuscationReport::new(&shatter_states).write(&src.target_dir)?;
+        if !matches.is_present("keep-paths") {
+            rustflags_env.push(path_scrub_env(&dest, &src.target_dir)?);
+        }
     }
 
     println!("Calling cargo");
@@ -147,6 +296,7 @@ fn main() -> io::Result<()> {
         .arg(&src.target_dir)
         .args(cargo_args)
         .current_dir(&dest)
+        .envs(rustflags_env)
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .output()
@@ -158,3 +308,84 @@ fn main() -> io::Result<()> {
 
     Ok(())
 }
+
+fn audit_strings(matches: &ArgMatches, obfuscate_config: &ObfuscateConfig) -> io::Result<()> {
+    let src = get_src_dir();
+    let entries = audit_strings_dir(&src.workspace_root, obfuscate_config)?;
+    let plaintext = entries.iter().filter(|entry| !entry.encrypted).count();
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+    } else {
+        let locations: Vec<String> = entries
+            .iter()
+            .map(|entry| format!("{}:{}:{}", entry.file, entry.line, entry.column))
+            .collect();
+        let width = locations.iter().map(String::len).max().unwrap_or(0);
+
+        println!("{:width$}  {:9}  {}", "LOCATION", "STATUS", "REASON");
+        for (location, entry) in locations.iter().zip(&entries) {
+            let status = if entry.encrypted {
+                "encrypted"
+            } else {
+                "plaintext"
+            };
+            let reason = entry
+                .reason
+                .map(|reason| reason.to_string())
+                .unwrap_or_default();
+            println!("{:width$}  {:9}  {}", location, status, reason);
+        }
+        println!(
+            "{} of {} string literals left unencrypted",
+            plaintext,
+            entries.len()
+        );
+    }
+
+    //Validator guarantees the budget parses
+    if let Some(budget) = matches.value_of("budget") {
+        if plaintext > budget.parse::<usize>().unwrap() {
+            eprintln!("Plaintext string budget of {} exceeded", budget);
+            std::process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+fn scan(matches: &ArgMatches) -> io::Result<()> {
+    let src = get_src_dir();
+    //Required argument, clap guarantees it's there
+    let binary = camino::Utf8PathBuf::from(matches.value_of("binary").unwrap());
+    let leaks = scan_workspace_binary(&binary, &src.workspace_root)?;
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&leaks).unwrap());
+    } else {
+        for leak in &leaks {
+            println!("{}", leak);
+        }
+        println!("{} leaks found in {}", leaks.len(), binary);
+    }
+
+    if !leaks.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn decode_panic(matches: &ArgMatches) -> io::Result<()> {
+    //Required arguments, validated by clap
+    let key = r2d2::panics::parse_key(matches.value_of("key").unwrap()).unwrap();
+    let report = matches.value_of("report").unwrap();
+
+    match r2d2::panics::decrypt_report(&key, report) {
+        Some(report) => println!("{}", report),
+        None => {
+            eprintln!("Report doesn't decrypt under this key");
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use quote::*;
+use syn::*;
+
+use crate::crypto::KEY_SHARE_SIZE;
+use crate::shatter::generate_unique_ident;
+use crate::strencrypt::MasterSecret;
+//Workaround to self obfuscate (since we can't add ourselves as a dependency)
+#[allow(unused_imports)]
+use crate as r2d2;
+
+/*
+ * Panic hardening
+ * Installs one of the r2d2::panics hooks as the first statement of main, so nothing that panics
+ * afterwards goes through the default hook
+ * Debug builds keep the default hook, the hook is gated on cfg(not(debug_assertions))
+ * Only meant for the roots of binary targets, build scripts don't depend on r2d2
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicMode {
+    //Leave the default hook alone
+    #[default]
+    Keep,
+    //Print nothing when panicking
+    Suppress,
+    //Print the panic details encrypted under this report key
+    Encrypt([u8; KEY_SHARE_SIZE]),
+}
+
+pub fn install_panic_hook(input: &mut File, mode: PanicMode) {
+    let mut items: Vec<Item> = Vec::new();
+
+    let install = match mode {
+        PanicMode::Keep => return,
+        PanicMode::Suppress => quote! { r2d2::panics::install_suppressed(); },
+        PanicMode::Encrypt(key) => {
+            //The key is split the same way string keys are, never sitting in one place
+            let secret = MasterSecret::split(&key);
+            items.extend(secret.to_items());
+
+            let shares_ident = generate_unique_ident();
+            let share_idents = secret.shares.iter().map(|(ident, _)| ident);
+            let count = secret.shares.len();
+            let tokens = quote! {
+                #[allow(non_upper_case_globals)]
+                static #shares_ident: [&[u8; #KEY_SHARE_SIZE]; #count] = [#(&#share_idents),*];
+            };
+            items.push(syn::parse2::<Item>(tokens).unwrap());
+            quote! { r2d2::panics::install_encrypted(&#shares_ident); }
+        }
+    };
+
+    //Only the entry point of a binary, fn main inside modules is just another function
+    let main = input.items.iter_mut().find_map(|item| match item {
+        Item::Fn(function) if function.sig.ident == "main" => Some(function),
+        _ => None,
+    });
+
+    if let Some(main) = main {
+        let stmt = syn::parse2::<Stmt>(quote! {
+            #[cfg(not(debug_assertions))]
+            #install
+        })
+        .unwrap();
+        main.block.stmts.insert(0, stmt);
+        input.items.extend(items);
+    }
+}
+
+#[cfg(test)]
+mod panic_hook_tests {
+    use crate::panichook::*;
+    use std::path::Path;
+
+    #[test]
+    fn installs_in_main_only() {
+        let mut file = syn::parse_file(
+            r#"
+            mod inner {
+                fn main() {}
+            }
+            fn main() {
+                println!("hi");
+            }
+            "#,
+        )
+        .unwrap();
+        install_panic_hook(&mut file, PanicMode::Encrypt([7; KEY_SHARE_SIZE]));
+        let output = prettyplease::unparse(&file);
+
+        assert_eq!(output.matches("install_encrypted").count(), 1);
+        let main = output.find("fn main() {\n    #[cfg").unwrap();
+        assert!(output[main..].contains("install_encrypted"));
+
+        let mut file = syn::parse_file("fn helper() {}").unwrap();
+        install_panic_hook(&mut file, PanicMode::Suppress);
+        assert_eq!(file.items.len(), 1);
+    }
+
+    #[test]
+    fn skips_build_scripts() {
+        let mut config = crate::ObfuscateConfig {
+            panics: PanicMode::Suppress,
+            ..Default::default()
+        };
+        config.binary_roots.insert("/crate/src/main.rs".into());
+        let source = String::from("fn main() {}");
+
+        let build = crate::obfuscate(&source, Some(Path::new("/crate/build.rs")), &config);
+        assert!(!build.source.contains("install_suppressed"));
+        let main = crate::obfuscate(&source, Some(Path::new("/crate/src/main.rs")), &config);
+        assert!(main.source.contains("install_suppressed"));
+    }
+}
@@ -0,0 +1,127 @@
+use chacha20poly1305::XChaCha20Poly1305;
+use rand::prelude::*;
+use rand::rngs::OsRng;
+use std::any::Any;
+use std::fmt::Write;
+use std::io::Write as IoWrite;
+
+use crate::crypto::{encrypt_memory_derived, try_decrypt_memory_derived, KEY_SHARE_SIZE};
+
+//Workaround to self obfuscate (since we can't add ourselves as a dependency)
+#[allow(unused_imports)]
+use crate as r2d2;
+
+/*
+ * Runtime support for panic hardening
+ * The default hook prints the panic message, its source location and possibly a backtrace, all
+ * of which are a gift to anyone reversing the binary
+ * Generated code installs one of these hooks at the start of main instead
+ * Neither hook ever prints a backtrace
+ */
+
+const REPORT_SALT_SIZE: usize = 16;
+
+//Prefix of the line printed by the encrypting hook, so reports are easy to grep out of logs
+pub const REPORT_PREFIX: &str = "panic report: ";
+
+//Print nothing at all, the process still unwinds or aborts as usual
+pub fn install_suppressed() {
+    std::panic::set_hook(Box::new(|_| {}));
+}
+
+/*
+ * Print the usual panic details encrypted under a key split across shares
+ * Only the holder of the report key can read them, see decrypt_report
+ */
+pub fn install_encrypted(shares: &'static [&'static [u8; KEY_SHARE_SIZE]]) {
+    std::panic::set_hook(Box::new(move |info| {
+        let thread = std::thread::current();
+        let mut report = format!("thread '{}' panicked", thread.name().unwrap_or("<unnamed>"));
+        if let Some(location) = info.location() {
+            let _ = write!(report, " at {}", location);
+        }
+        let _ = write!(report, ":\n{}", payload_message(info.payload()));
+
+        let encrypted = encrypt_report(shares, report.as_bytes());
+        //Nothing sensible left to do if stderr is gone
+        let _ = writeln!(std::io::stderr(), "{}{}", REPORT_PREFIX, encrypted);
+    }));
+}
+
+//panic! with a format string carries a String, a bare literal carries a &str
+fn payload_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+//Hex of a fresh salt followed by the ciphertext
+fn encrypt_report(shares: &[&[u8; KEY_SHARE_SIZE]], report: &[u8]) -> String {
+    let mut salt = [0u8; REPORT_SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    let ctx = encrypt_memory_derived::<XChaCha20Poly1305>(shares, &salt, report);
+
+    let mut output = String::with_capacity((salt.len() + ctx.ciphertext.len()) * 2);
+    for byte in salt.iter().chain(ctx.ciphertext.iter()) {
+        let _ = write!(output, "{:02x}", byte);
+    }
+    output
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+pub fn parse_key(hex: &str) -> Option<[u8; KEY_SHARE_SIZE]> {
+    from_hex(hex.trim())?.try_into().ok()
+}
+
+pub fn format_key(key: &[u8; KEY_SHARE_SIZE]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+//Accepts a report with or without the prefix, returns None for the wrong key or a mangled report
+pub fn decrypt_report(key: &[u8; KEY_SHARE_SIZE], report: &str) -> Option<String> {
+    let report = report.trim();
+    let report = report.strip_prefix(REPORT_PREFIX).unwrap_or(report);
+    let data = from_hex(report)?;
+    if data.len() < REPORT_SALT_SIZE {
+        return None;
+    }
+
+    let (salt, ciphertext) = data.split_at(REPORT_SALT_SIZE);
+    let plaintext = try_decrypt_memory_derived::<XChaCha20Poly1305>(&[key], salt, ciphertext)?;
+    String::from_utf8(plaintext).ok()
+}
+
+#[cfg(test)]
+mod panic_report_tests {
+    use crate::panics::*;
+
+    #[test]
+    fn report_round_trip() {
+        let shares: [[u8; KEY_SHARE_SIZE]; 2] = [[0x42; KEY_SHARE_SIZE], [0x17; KEY_SHARE_SIZE]];
+        let mut key = [0u8; KEY_SHARE_SIZE];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = shares[0][i] ^ shares[1][i];
+        }
+
+        let report = encrypt_report(&[&shares[0], &shares[1]], b"it broke");
+        let line = format!("{}{}\n", REPORT_PREFIX, report);
+        assert_eq!(decrypt_report(&key, &line).as_deref(), Some("it broke"));
+        assert_eq!(decrypt_report(&[0u8; KEY_SHARE_SIZE], &report), None);
+        assert_eq!(decrypt_report(&key, "not hex"), None);
+
+        assert_eq!(parse_key(&format_key(&key)), Some(key));
+    }
+}
@@ -102,6 +102,18 @@ impl Parse for AssertArgs {
     }
 }
 
+impl ToTokens for AssertArgs {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let condition = &self.condition;
+        tokens.append_all(quote! { #condition });
+
+        if let Some(message) = &self.message {
+            tokens.append(Punct::new(',', Spacing::Alone));
+            message.to_tokens(tokens);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AssertCmpArgs {
     pub first_condition: Expr,
@@ -129,3 +141,16 @@ impl Parse for AssertCmpArgs {
         })
     }
 }
+
+impl ToTokens for AssertCmpArgs {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let first = &self.first_condition;
+        let second = &self.second_condition;
+        tokens.append_all(quote! { #first, #second });
+
+        if let Some(message) = &self.message {
+            tokens.append(Punct::new(',', Spacing::Alone));
+            message.to_tokens(tokens);
+        }
+    }
+}
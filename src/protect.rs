@@ -0,0 +1,228 @@
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::ptr::{self, NonNull};
+use std::sync::Mutex;
+use zeroize::Zeroize;
+
+//Workaround to self obfuscate (since we can't add ourselves as a dependency)
+#[allow(unused_imports)]
+use crate as r2d2;
+
+/*
+ * Dedicated pages backing encrypted containers
+ * Locked in memory so they never hit swap, left out of core dumps, and inaccessible entirely
+ * while their contents are encrypted
+ * Every protection is best effort, a container that can't get one still works without it
+ * Locking in particular fails as soon as RLIMIT_MEMLOCK runs out, which containers set low
+ */
+pub(crate) struct ProtectedPages {
+    ptr: NonNull<u8>,
+    //Whole pages, at least the requested size
+    len: usize,
+    //Came from mmap, so mprotect and friends apply, otherwise a plain heap fallback
+    mapped: bool,
+    locked: bool,
+    //Threads inside read, the protections are only toggled with this held
+    readers: Mutex<usize>,
+}
+
+/*
+ * The pages are owned outright, and shared references only ever get to them through read, which
+ * keeps the protections consistent between threads
+ */
+unsafe impl Send for ProtectedPages {}
+unsafe impl Sync for ProtectedPages {}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    //SAFETY: No preconditions, and _SC_PAGESIZE can't fail on any supported platform
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    4096
+}
+
+impl ProtectedPages {
+    //Zeroed and accessible, size has to be non-zero
+    pub(crate) fn new(size: usize) -> Self {
+        let page = page_size();
+        let len = (size.max(1) + page - 1) / page * page;
+
+        if let Some(pages) = Self::map(len) {
+            return pages;
+        }
+
+        //Page alignment keeps the fallback usable for any type the mapped pages would be
+        let layout = Layout::from_size_align(len, page).unwrap();
+        //SAFETY: Non-zero size
+        let ptr = unsafe { alloc_zeroed(layout) };
+        ProtectedPages {
+            ptr: NonNull::new(ptr).expect("Out of memory"),
+            len,
+            mapped: false,
+            locked: false,
+            readers: Mutex::new(0),
+        }
+    }
+
+    #[cfg(unix)]
+    fn map(len: usize) -> Option<Self> {
+        //SAFETY: Anonymous private mapping, nothing else refers to the pages
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+
+        //SAFETY: Operating on the mapping we just created
+        let locked = unsafe { libc::mlock(ptr, len) } == 0;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        unsafe {
+            libc::madvise(ptr, len, libc::MADV_DONTDUMP);
+        }
+
+        Some(ProtectedPages {
+            ptr: NonNull::new(ptr as *mut u8)?,
+            len,
+            mapped: true,
+            locked,
+            readers: Mutex::new(0),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn map(_len: usize) -> Option<Self> {
+        None
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    //Whether the pages are kept out of swap
+    pub(crate) fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /*
+     * Any access while inaccessible faults instead of quietly reading ciphertext
+     * Takes &mut so it can't happen while someone is in read
+     */
+    pub(crate) fn set_accessible(&mut self, accessible: bool) {
+        self.protect(accessible);
+    }
+
+    /*
+     * Reads pages that are otherwise inaccessible, from any number of threads at once
+     * The first reader in makes them accessible and the last one out revokes it, with the count
+     * locked across both so no reader loses access while another is still copying
+     */
+    pub(crate) fn read<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        {
+            let mut readers = self.readers.lock().unwrap();
+            if *readers == 0 {
+                self.protect(true);
+            }
+            *readers += 1;
+        }
+        //SAFETY: Accessible until the last reader leaves
+        let result = f(unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) });
+
+        let mut readers = self.readers.lock().unwrap();
+        *readers -= 1;
+        if *readers == 0 {
+            self.protect(false);
+        }
+        result
+    }
+
+    fn protect(&self, accessible: bool) {
+        if !self.mapped {
+            return;
+        }
+        #[cfg(unix)]
+        {
+            let protection = if accessible {
+                libc::PROT_READ | libc::PROT_WRITE
+            } else {
+                libc::PROT_NONE
+            };
+            //SAFETY: Our own mapping, and nothing holds a reference into it across this call
+            unsafe {
+                libc::mprotect(self.ptr.as_ptr() as *mut libc::c_void, self.len, protection);
+            }
+        }
+    }
+}
+
+impl Drop for ProtectedPages {
+    fn drop(&mut self) {
+        self.set_accessible(true);
+        //SAFETY: The whole allocation, accessible again
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }.zeroize();
+
+        if !self.mapped {
+            let layout = Layout::from_size_align(self.len, page_size()).unwrap();
+            //SAFETY: Allocated in new with this exact layout
+            unsafe { dealloc(self.ptr.as_ptr(), layout) };
+            return;
+        }
+        #[cfg(unix)]
+        unsafe {
+            //SAFETY: Our own mapping, never used again
+            let ptr = self.ptr.as_ptr() as *mut libc::c_void;
+            if self.locked {
+                libc::munlock(ptr, self.len);
+            }
+            libc::munmap(ptr, self.len);
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod protect_tests {
+    use crate::protect::*;
+
+    //Permissions of the mapping containing ptr, straight from the kernel
+    fn permissions(ptr: *mut u8) -> String {
+        let address = ptr as usize;
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        maps.lines()
+            .find_map(|line| {
+                let (range, rest) = line.split_once(' ')?;
+                let (start, end) = range.split_once('-')?;
+                let start = usize::from_str_radix(start, 16).ok()?;
+                let end = usize::from_str_radix(end, 16).ok()?;
+                (start <= address && address < end).then(|| rest[..4].to_string())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn toggles_access() {
+        let mut pages = ProtectedPages::new(100);
+        assert!(pages.mapped);
+        unsafe { pages.as_ptr().write(0x42) };
+        assert!(permissions(pages.as_ptr()).starts_with("rw"));
+
+        pages.set_accessible(false);
+        assert!(permissions(pages.as_ptr()).starts_with("---"));
+
+        pages.set_accessible(true);
+        assert_eq!(unsafe { pages.as_ptr().read() }, 0x42);
+
+        //Readers leave the pages as inaccessible as they found them
+        pages.set_accessible(false);
+        assert_eq!(pages.read(|bytes| bytes[0]), 0x42);
+        assert!(permissions(pages.as_ptr()).starts_with("---"));
+    }
+}
@@ -0,0 +1,161 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::MetadataCommand;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind};
+
+use crate::crypto::{keyed_hash, Blake2sMac256};
+
+/*
+ * Source path scrubbing
+ * Every panic location, file!() and debug info entry carries the path rustc was handed for a
+ * source file, which gives away the build machine layout and the module structure of the crate
+ * We hand rustc a --remap-path-prefix for every directory and workspace source file we know of,
+ * each pointing at a name hashed from the path under a random per workspace salt
+ * The names have to stay the same between builds, cargo rebuilds everything in the target
+ * directory whenever the flags change
+ */
+
+//The separator cargo expects between flags in CARGO_ENCODED_RUSTFLAGS
+const FLAG_SEPARATOR: char = '\x1f';
+
+//Environment variable holding the flags for the cargo invocation
+pub const RUSTFLAGS_VAR: &str = "CARGO_ENCODED_RUSTFLAGS";
+
+//Kept in the target directory, so it goes away with cargo clean like the cache it protects
+const SALT_FILE: &str = "r2d2-remap-salt";
+const SALT_SIZE: usize = 32;
+
+fn load_salt(target_dir: &Utf8Path) -> io::Result<[u8; SALT_SIZE]> {
+    let path = target_dir.join(SALT_FILE);
+    if let Ok(Ok(salt)) = fs::read(&path).map(<[u8; SALT_SIZE]>::try_from) {
+        return Ok(salt);
+    }
+    let mut salt = [0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    fs::create_dir_all(target_dir)?;
+    fs::write(&path, salt)?;
+    Ok(salt)
+}
+
+//Without the salt, names can't be matched back to paths by hashing guesses
+fn remap_name(salt: &[u8], path: &str) -> String {
+    keyed_hash::<Blake2sMac256>(salt, path.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/*
+ * Builds the (from, to) remaps for a build directory, with the salt kept in target_dir
+ * rustc uses the last matching remap, so the broad ones come first and the files last
+ * Workspace members are compiled with paths relative to the workspace root, dependencies with
+ * absolute paths, so files get both forms
+ */
+pub fn path_remaps(
+    build_root: &Utf8Path,
+    target_dir: &Utf8Path,
+) -> io::Result<Vec<(String, String)>> {
+    let metadata = MetadataCommand::new()
+        .current_dir(build_root)
+        .exec()
+        .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+    let salt = load_salt(target_dir)?;
+    let name = |path: &str| remap_name(&salt, path);
+
+    let mut remaps: Vec<(String, String)> = Vec::new();
+
+    remaps.push((build_root.to_string(), name(build_root.as_str())));
+    if let Some(cargo_home) = cargo_home() {
+        remaps.push((cargo_home.to_string(), name(cargo_home.as_str())));
+    }
+
+    //Dependency directories give away the crate names and versions in use
+    let members: HashSet<_> = metadata.workspace_members.iter().collect();
+    for package in &metadata.packages {
+        if members.contains(&package.id) {
+            continue;
+        }
+        let dir = package.manifest_path.parent().unwrap();
+        remaps.push((dir.to_string(), name(dir.as_str())));
+    }
+
+    //Only workspace files are named one by one, a flag per dependency file would blow past the
+    //environment size limit
+    for file_path in crate::workspace_source_files(&metadata.workspace_root)? {
+        let file_path = Utf8PathBuf::from_path_buf(file_path)
+            .map_err(|p| io::Error::new(ErrorKind::InvalidData, p.display().to_string()))?;
+        let to = format!("{}.rs", name(file_path.as_str()));
+        if let Ok(relative) = file_path.strip_prefix(&metadata.workspace_root) {
+            remaps.push((relative.to_string(), to.to_owned()));
+        }
+        remaps.push((file_path.to_string(), to));
+    }
+
+    Ok(remaps)
+}
+
+fn cargo_home() -> Option<Utf8PathBuf> {
+    if let Ok(home) = env::var("CARGO_HOME") {
+        return Some(Utf8PathBuf::from(home));
+    }
+    env::var("HOME")
+        .ok()
+        .map(|home| Utf8PathBuf::from(home).join(".cargo"))
+}
+
+/*
+ * Encodes the remaps as a CARGO_ENCODED_RUSTFLAGS value, keeping flags the user already passed
+ * through the environment
+ * Setting this overrides build.rustflags from cargo config files, same as RUSTFLAGS does
+ */
+pub fn encoded_rustflags(remaps: &[(String, String)]) -> String {
+    let mut flags: Vec<String> = match env::var(RUSTFLAGS_VAR) {
+        Ok(encoded) if !encoded.is_empty() => {
+            encoded.split(FLAG_SEPARATOR).map(str::to_string).collect()
+        }
+        _ => env::var("RUSTFLAGS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect(),
+    };
+    for (from, to) in remaps {
+        flags.push(format!("--remap-path-prefix={}={}", from, to));
+    }
+    flags.join(&FLAG_SEPARATOR.to_string())
+}
+
+#[cfg(test)]
+mod remap_tests {
+    use crate::remap::*;
+
+    #[test]
+    fn remaps_ordered() {
+        let root = Utf8PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let target_dir = root.join("target");
+        let remaps = path_remaps(&root, &target_dir).unwrap();
+        //Same salt, same names, so cargo keeps its cache between builds
+        assert_eq!(path_remaps(&root, &target_dir).unwrap(), remaps);
+
+        //The root catch-all has to lose to every file remap
+        assert_eq!(remaps[0].0, root.as_str());
+        let lib = remaps
+            .iter()
+            .position(|(from, _)| from == "src/lib.rs")
+            .unwrap();
+        assert!(lib > 0);
+        assert!(remaps[lib].1.ends_with(".rs"));
+        assert!(!remaps[lib].1.contains("lib"));
+
+        //Both forms of a file share a name
+        let absolute = root.join("src/lib.rs");
+        assert_eq!(
+            remaps[lib + 1],
+            (absolute.to_string(), remaps[lib].1.to_owned())
+        );
+    }
+}
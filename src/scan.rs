@@ -0,0 +1,356 @@
+use goblin::elf::section_header::{SHF_ALLOC, SHT_NOBITS};
+use goblin::elf::Elf;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use syn::spanned::Spanned;
+use syn::*;
+
+//Workaround to self obfuscate (since we can't add ourselves as a dependency)
+#[allow(unused_imports)]
+use crate as r2d2;
+
+/*
+ * Post-build leak scanner
+ * Looks for things from the source tree that shouldn't survive obfuscation in the loadable
+ * sections of a compiled binary
+ */
+
+//Anything shorter turns up by chance in any binary
+const MIN_NEEDLE_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NeedleKind {
+    StringLiteral,
+    SourcePath,
+    CrateName,
+    ModuleName,
+    TypeName,
+}
+
+impl std::fmt::Display for NeedleKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NeedleKind::StringLiteral => write!(f, "string literal"),
+            NeedleKind::SourcePath => write!(f, "source path"),
+            NeedleKind::CrateName => write!(f, "crate name"),
+            NeedleKind::ModuleName => write!(f, "module name"),
+            NeedleKind::TypeName => write!(f, "type name"),
+        }
+    }
+}
+
+//Something to look for in the binary, and everywhere in the source it came from
+#[derive(Debug, Clone)]
+pub struct Needle {
+    pub kind: NeedleKind,
+    pub bytes: Vec<u8>,
+    pub origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Leak {
+    pub section: String,
+    //Offset into the file
+    pub offset: u64,
+    pub address: u64,
+    pub kind: NeedleKind,
+    pub text: String,
+    pub origins: Vec<String>,
+}
+
+impl std::fmt::Display for Leak {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:#x} (file offset {:#x}): {} {:?} from {}",
+            self.section,
+            self.address,
+            self.offset,
+            self.kind,
+            self.text,
+            self.origins.join(", ")
+        )
+    }
+}
+
+/*
+ * Gathers needles from source files, merging duplicates so each one is only searched for once
+ * Keyed by kind as well, so a literal that happens to match a type name reports both
+ */
+#[derive(Default)]
+pub struct NeedleSet {
+    needles: BTreeMap<(NeedleKind, Vec<u8>), Vec<String>>,
+}
+
+impl NeedleSet {
+    pub fn add(&mut self, kind: NeedleKind, bytes: &[u8], origin: String) {
+        if bytes.len() < MIN_NEEDLE_LEN {
+            return;
+        }
+        self.needles
+            .entry((kind, bytes.to_vec()))
+            .or_default()
+            .push(origin);
+    }
+
+    //file is reported as given, source is the parsed contents of that file
+    pub fn add_source(&mut self, file: &Path, source: &File, crate_name: Option<&str>) {
+        let file_name = file.display().to_string();
+        self.add(
+            NeedleKind::SourcePath,
+            file_name.as_bytes(),
+            file_name.to_owned(),
+        );
+
+        //File modules, main.rs/lib.rs/mod.rs are named after their crate or directory instead
+        let module = match file.file_stem().and_then(|stem| stem.to_str()) {
+            Some("main" | "lib" | "build") => None,
+            Some("mod") => file
+                .parent()
+                .and_then(|dir| dir.file_name())
+                .and_then(|name| name.to_str()),
+            stem => stem,
+        };
+        if let (Some(crate_name), Some(module)) = (crate_name, module) {
+            let path = format!("{}::{}", crate_name, module);
+            self.add(
+                NeedleKind::ModuleName,
+                path.as_bytes(),
+                file_name.to_owned(),
+            );
+        }
+
+        let mut finder = NeedleFinder {
+            set: self,
+            file_name,
+            module_path: crate_name.into_iter().map(str::to_string).collect(),
+        };
+        if let Some(module) = module {
+            finder.module_path.push(module.to_string());
+        }
+        syn::visit::Visit::visit_file(&mut finder, source);
+    }
+
+    pub fn into_needles(self) -> Vec<Needle> {
+        self.needles
+            .into_iter()
+            .map(|((kind, bytes), origins)| Needle {
+                kind,
+                bytes,
+                origins,
+            })
+            .collect()
+    }
+}
+
+struct NeedleFinder<'a> {
+    set: &'a mut NeedleSet,
+    file_name: String,
+    //Crate name followed by the modules leading to the current item
+    module_path: Vec<String>,
+}
+
+impl<'a> NeedleFinder<'a> {
+    fn origin(&self, span: proc_macro2::Span) -> String {
+        format!("{}:{}", self.file_name, span.start().line)
+    }
+
+    fn add_type(&mut self, ident: &Ident) {
+        let origin = self.origin(ident.span());
+        self.set
+            .add(NeedleKind::TypeName, ident.to_string().as_bytes(), origin);
+    }
+}
+
+impl<'a, 'ast> syn::visit::Visit<'ast> for NeedleFinder<'a> {
+    fn visit_lit_str(&mut self, node: &'ast LitStr) {
+        let origin = self.origin(node.span());
+        self.set
+            .add(NeedleKind::StringLiteral, node.value().as_bytes(), origin);
+    }
+
+    fn visit_lit_byte_str(&mut self, node: &'ast LitByteStr) {
+        let origin = self.origin(node.span());
+        self.set
+            .add(NeedleKind::StringLiteral, &node.value(), origin);
+    }
+
+    //Literals in macro bodies are only tokens, like println!("...")
+    fn visit_macro(&mut self, node: &'ast Macro) {
+        let mut literals: Vec<LitStr> = Vec::new();
+        collect_token_literals(node.tokens.to_owned(), &mut literals);
+        for literal in literals {
+            self.visit_lit_str(&literal);
+        }
+    }
+
+    fn visit_item_mod(&mut self, node: &'ast ItemMod) {
+        self.module_path.push(node.ident.to_string());
+        let origin = self.origin(node.span());
+        self.set.add(
+            NeedleKind::ModuleName,
+            self.module_path.join("::").as_bytes(),
+            origin,
+        );
+        syn::visit::visit_item_mod(self, node);
+        self.module_path.pop();
+    }
+
+    fn visit_item_struct(&mut self, node: &'ast ItemStruct) {
+        self.add_type(&node.ident);
+        syn::visit::visit_item_struct(self, node);
+    }
+
+    fn visit_item_enum(&mut self, node: &'ast ItemEnum) {
+        self.add_type(&node.ident);
+        syn::visit::visit_item_enum(self, node);
+    }
+
+    fn visit_item_union(&mut self, node: &'ast ItemUnion) {
+        self.add_type(&node.ident);
+        syn::visit::visit_item_union(self, node);
+    }
+
+    fn visit_item_trait(&mut self, node: &'ast ItemTrait) {
+        self.add_type(&node.ident);
+        syn::visit::visit_item_trait(self, node);
+    }
+}
+
+fn collect_token_literals(tokens: proc_macro2::TokenStream, literals: &mut Vec<LitStr>) {
+    for token in tokens {
+        match token {
+            proc_macro2::TokenTree::Group(group) => {
+                collect_token_literals(group.stream(), literals)
+            }
+            proc_macro2::TokenTree::Literal(literal) => {
+                let tokens = proc_macro2::TokenTree::Literal(literal).into();
+                if let Ok(literal) = syn::parse2::<LitStr>(tokens) {
+                    literals.push(literal);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/*
+ * Searches every loadable section of an ELF binary for the needles
+ * Sections without file contents (.bss) are skipped, there's nothing to leak there
+ */
+pub fn scan_binary(binary: &[u8], needles: &[Needle]) -> goblin::error::Result<Vec<Leak>> {
+    let elf = Elf::parse(binary)?;
+
+    //Index needles by their first few bytes, so each position costs a single lookup
+    let mut prefixes: HashMap<&[u8], Vec<&Needle>> = HashMap::new();
+    for needle in needles {
+        if needle.bytes.len() >= MIN_NEEDLE_LEN {
+            prefixes
+                .entry(&needle.bytes[..MIN_NEEDLE_LEN])
+                .or_default()
+                .push(needle);
+        }
+    }
+
+    let mut leaks: Vec<Leak> = Vec::new();
+
+    for header in &elf.section_headers {
+        if header.sh_flags & SHF_ALLOC as u64 == 0 || header.sh_type == SHT_NOBITS {
+            continue;
+        }
+        let section = elf
+            .shdr_strtab
+            .get_at(header.sh_name)
+            .unwrap_or_default()
+            .to_string();
+        let range = match header.file_range() {
+            Some(range) if range.end <= binary.len() => range,
+            _ => continue,
+        };
+        let data = &binary[range.to_owned()];
+
+        for position in 0..data.len().saturating_sub(MIN_NEEDLE_LEN - 1) {
+            let candidates = match prefixes.get(&data[position..position + MIN_NEEDLE_LEN]) {
+                Some(candidates) => candidates,
+                None => continue,
+            };
+            for needle in candidates {
+                if data[position..].starts_with(&needle.bytes) {
+                    leaks.push(Leak {
+                        section: section.to_owned(),
+                        offset: (range.start + position) as u64,
+                        address: header.sh_addr + position as u64,
+                        kind: needle.kind,
+                        text: String::from_utf8_lossy(&needle.bytes).into_owned(),
+                        origins: needle.origins.to_owned(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(leaks)
+}
+
+#[cfg(test)]
+mod scan_tests {
+    use crate::scan::*;
+
+    static HAYSTACK: &str = "needle-in-a-haystack-0451";
+
+    #[test]
+    fn scan_self() {
+        let mut set = NeedleSet::default();
+        set.add(
+            NeedleKind::StringLiteral,
+            HAYSTACK.as_bytes(),
+            String::from("scan.rs"),
+        );
+        //Too short to search for
+        set.add(NeedleKind::StringLiteral, b"abc", String::from("scan.rs"));
+        let needles = set.into_needles();
+        assert_eq!(needles.len(), 1);
+
+        let binary = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let leaks = scan_binary(&binary, &needles).unwrap();
+        assert!(leaks.iter().any(|leak| leak.text == HAYSTACK
+            && leak.section.starts_with(".rodata")
+            && binary[leak.offset as usize..].starts_with(HAYSTACK.as_bytes())));
+    }
+
+    #[test]
+    fn needles_from_source() {
+        let source = syn::parse_file(
+            r#"
+            mod inner {
+                struct Config;
+            }
+            fn main() {
+                println!("hello {}", "world");
+            }
+            "#,
+        )
+        .unwrap();
+        let mut set = NeedleSet::default();
+        set.add_source(Path::new("src/tool.rs"), &source, Some("mycrate"));
+
+        let mut found: Vec<(NeedleKind, String)> = set
+            .into_needles()
+            .into_iter()
+            .map(|needle| (needle.kind, String::from_utf8(needle.bytes).unwrap()))
+            .collect();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                (NeedleKind::StringLiteral, String::from("hello {}")),
+                (NeedleKind::StringLiteral, String::from("world")),
+                (NeedleKind::SourcePath, String::from("src/tool.rs")),
+                (NeedleKind::ModuleName, String::from("mycrate::tool")),
+                (NeedleKind::ModuleName, String::from("mycrate::tool::inner")),
+                (NeedleKind::TypeName, String::from("Config")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use quote::*;
+use syn::visit::Visit;
+use syn::visit_mut::{self, VisitMut};
+use syn::*;
+
+//Workaround to self obfuscate (since we can't add ourselves as a dependency)
+#[allow(unused_imports)]
+use crate as r2d2;
+
+/*
+ * Stack scrubbing for functions marked #[r2d2::scrub_stack], or #[r2d2::scrub_stack(BYTES)] to
+ * pick the amount
+ * The body moves into a closure run through r2d2::stack::scrub_after, so return and ? leave the
+ * closure rather than the function, and the stack is scrubbed on every way out
+ * Async functions aren't supported, their locals don't live on the stack to begin with
+ * Functions the pass can't handle are left as they are, with a compile_error! at the start of
+ * their body
+ */
+
+const SCRUB_ATTRIBUTE: &str = "scrub_stack";
+
+//Either the bare name or r2d2::scrub_stack
+fn is_scrub_attribute(attr: &Attribute) -> bool {
+    let segments: Vec<String> = attr
+        .path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+    match segments.as_slice() {
+        [name] => name == SCRUB_ATTRIBUTE,
+        [krate, name] => krate == "r2d2" && name == SCRUB_ATTRIBUTE,
+        _ => false,
+    }
+}
+
+/*
+ * Removes the attribute, returning how many bytes it asked for, if it was there
+ * Anything but a byte count is an error with the message for compile_error!
+ */
+fn take_scrub_attribute(
+    attrs: &mut Vec<Attribute>,
+    default_bytes: usize,
+) -> Option<std::result::Result<usize, String>> {
+    let position = attrs.iter().position(is_scrub_attribute)?;
+    let attr = attrs.remove(position);
+    if attr.tokens.is_empty() {
+        return Some(Ok(default_bytes));
+    }
+    let bytes = attr
+        .parse_args::<LitInt>()
+        .ok()
+        //4k lexes as 4 with a suffix
+        .filter(|bytes| bytes.suffix().is_empty())
+        .and_then(|bytes| bytes.base10_parse::<usize>().ok())
+        .ok_or_else(|| {
+            format!(
+                "#[scrub_stack] takes a byte count: #[scrub_stack{}]",
+                attr.tokens
+            )
+        });
+    Some(bytes)
+}
+
+struct ImplTraitFinder {
+    found: bool,
+}
+
+impl<'ast> Visit<'ast> for ImplTraitFinder {
+    fn visit_type_impl_trait(&mut self, _ty: &'ast TypeImplTrait) {
+        self.found = true;
+    }
+}
+
+fn wrap_body(sig: &Signature, block: &mut Block, bytes: std::result::Result<usize, String>) {
+    let bytes = match bytes {
+        Ok(_) if sig.asyncness.is_some() => Err(format!(
+            "#[scrub_stack] doesn't support async fn {}",
+            sig.ident
+        )),
+        bytes => bytes,
+    };
+    let bytes = match bytes {
+        Ok(bytes) => bytes,
+        Err(message) => {
+            let error: Stmt = parse_quote!(compile_error!(#message););
+            block.stmts.insert(0, error);
+            return;
+        }
+    };
+
+    /*
+     * The closure needs the return type spelled out for ? to know what to convert errors into
+     * Closures can't return impl Trait, those are left to inference
+     */
+    let output = match &sig.output {
+        ReturnType::Default => Some(quote!(-> ())),
+        ReturnType::Type(_, ty) => {
+            let mut finder = ImplTraitFinder { found: false };
+            finder.visit_type(ty);
+            (!finder.found).then(|| quote!(-> #ty))
+        }
+    };
+    let body = match output {
+        Some(output) => quote!(|| #output #block),
+        None => quote!(|| #block),
+    };
+    let bytes = LitInt::new(&bytes.to_string(), proc_macro2::Span::call_site());
+    *block = parse_quote! {{
+        r2d2::stack::scrub_after::<#bytes, _, _>(#body)
+    }};
+}
+
+struct ScrubStack {
+    default_bytes: usize,
+}
+
+impl VisitMut for ScrubStack {
+    fn visit_item_fn_mut(&mut self, item: &mut ItemFn) {
+        if let Some(bytes) = take_scrub_attribute(&mut item.attrs, self.default_bytes) {
+            wrap_body(&item.sig, &mut item.block, bytes);
+        }
+        visit_mut::visit_item_fn_mut(self, item);
+    }
+
+    fn visit_impl_item_method_mut(&mut self, item: &mut ImplItemMethod) {
+        if let Some(bytes) = take_scrub_attribute(&mut item.attrs, self.default_bytes) {
+            wrap_body(&item.sig, &mut item.block, bytes);
+        }
+        visit_mut::visit_impl_item_method_mut(self, item);
+    }
+
+    //Default bodies of trait methods
+    fn visit_trait_item_method_mut(&mut self, item: &mut TraitItemMethod) {
+        if let Some(bytes) = take_scrub_attribute(&mut item.attrs, self.default_bytes) {
+            if let Some(block) = &mut item.default {
+                wrap_body(&item.sig, block, bytes);
+            }
+        }
+        visit_mut::visit_trait_item_method_mut(self, item);
+    }
+}
+
+pub fn scrub_stacks(input: &mut File, default_bytes: usize) {
+    ScrubStack { default_bytes }.visit_file_mut(input);
+}
+
+#[cfg(test)]
+mod scrub_stack_pass_tests {
+    use crate::scrubstack::*;
+
+    #[test]
+    fn wraps_marked_bodies() {
+        let mut file = syn::parse_file(
+            r#"
+            #[r2d2::scrub_stack]
+            fn load(path: &str) -> Result<String, Error> {
+                let key = read(path)?;
+                Ok(key)
+            }
+            struct Vault;
+            impl Vault {
+                #[scrub_stack(4096)]
+                fn items(&self) -> impl Iterator<Item = u8> {
+                    Vec::new().into_iter()
+                }
+            }
+            fn untouched() {}
+            "#,
+        )
+        .unwrap();
+        scrub_stacks(&mut file, 1024);
+        let output: String = prettyplease::unparse(&file).split_whitespace().collect();
+
+        assert!(!output.contains("scrub_stack]"));
+        //Wrapped generic arguments get a trailing comma
+        let output = output.replace(",>", ">");
+        assert!(output.contains(
+            "r2d2::stack::scrub_after::<1024,_,_>(||->Result<String,Error>{letkey=read(path)?;"
+        ));
+        assert!(output.contains("r2d2::stack::scrub_after::<4096,_,_>(||{"));
+        assert!(output.contains("fnuntouched(){}"));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut file = syn::parse_file(
+            r#"
+            #[scrub_stack(4k)]
+            fn typo() -> u32 {
+                1
+            }
+            #[r2d2::scrub_stack]
+            async fn fetch() {}
+            "#,
+        )
+        .unwrap();
+        scrub_stacks(&mut file, 1024);
+        let output: String = prettyplease::unparse(&file).split_whitespace().collect();
+
+        //Left unwrapped, with the error in front of the original body
+        assert!(!output.contains("scrub_after"));
+        assert!(output.contains(
+            "fntypo()->u32{compile_error!(\"#[scrub_stack]takesabytecount:#[scrub_stack(4k)]\");1}"
+        ));
+        assert!(output.contains(
+            "asyncfnfetch(){compile_error!(\"#[scrub_stack]doesn'tsupportasyncfnfetch\");}"
+        ));
+    }
+}
@@ -12,6 +12,8 @@ use syn::*;
 use camino::Utf8PathBuf;
 use std::cmp::{Eq, PartialEq};
 
+use crate::crypto::HashAlgorithm;
+use crate::decoy::{DecoyConfig, Decoys};
 use crate::parse::*;
 
 //Workaround to self obfuscate (since we can't add ourselves as a dependency)
@@ -73,18 +75,38 @@ pub enum IntegrityCheckType {
     //TODO: Define and implement other variations like every Nth byte
 }
 
-//TODO: Make this generic over the digest used
 #[derive(Debug, Clone)]
 pub struct IntegrityCheck {
     pub check_type: IntegrityCheckType,
+    //Only read back by the windows post compilation pass
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    pub algorithm: HashAlgorithm,
     //TODO: Use a fixed size buffer instead of a vec
     pub hash: Vec<u8>,
     pub salt: Vec<u8>,
 }
 
+//Path to the algorithm in generated code
+impl ToTokens for HashAlgorithm {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let variant = match self {
+            HashAlgorithm::Blake2b512 => quote!(Blake2b512),
+            HashAlgorithm::Blake2b256 => quote!(Blake2b256),
+            HashAlgorithm::Blake2s256 => quote!(Blake2s256),
+            HashAlgorithm::Blake2bMac512 => quote!(Blake2bMac512),
+            HashAlgorithm::Blake2sMac256 => quote!(Blake2sMac256),
+            HashAlgorithm::HmacBlake2b512 => quote!(HmacBlake2b512),
+            HashAlgorithm::HmacBlake2s256 => quote!(HmacBlake2s256),
+        };
+        tokens.extend(quote! { r2d2::crypto::HashAlgorithm::#variant });
+    }
+}
+
 pub struct Shatter {
     inside_unsafe_block: bool,
-    integrity_checks: Vec<IntegrityCheck>
+    integrity_checks: Vec<IntegrityCheck>,
+    integrity_hash: HashAlgorithm,
+    decoys: Decoys,
 }
 
 //TODO: Is it better to type check this and pay the double conversion cost?
@@ -94,7 +116,13 @@ pub struct ShatterCondition {
     check: TokenStream,
 }
 
-fn generate_unique_ident() -> proc_macro2::Ident {
+//Rabbit hole for other passes to jump into, has to end up inside an unsafe block
+pub(crate) fn generate_rabbit_hole() -> Block {
+    //TODO: Have non-asm rabbit holes, and randomly choose between asm and generic here
+    arch::generate_rabbit_hole()
+}
+
+pub(crate) fn generate_unique_ident() -> proc_macro2::Ident {
     //Append a random 256 bit integer, if this ever has a collision, buy a lottery ticket!
     format_ident!(
         "var_{:x}{:x}{:x}{:x}",
@@ -126,7 +154,7 @@ impl Shatter {
 
     //TODO: Implement
     fn generate_integrity_check(&mut self) -> ShatterCondition {
-        let (cond, check) = os::generate_integrity_check();
+        let (cond, check) = os::generate_integrity_check(self.integrity_hash);
         self.integrity_checks.push(check);
         cond
     }
@@ -223,8 +251,7 @@ impl Shatter {
     }
 
     fn generate_rabbit_hole(&mut self) -> Block {
-        //TODO: Have non-asm rabbit holes, and randomly choose between asm and generic here
-        arch::generate_rabbit_hole()
+        generate_rabbit_hole()
     }
 
     fn generate_shatter_statement(&mut self, shatter_type: ShatterType) -> Block {
@@ -255,11 +282,13 @@ impl Shatter {
 
     fn inject_branch(&mut self) -> Vec<Stmt> {
         let ShatterCondition { setup, check } = self.generate_branch_condition();
+        let decoy = self.decoys.branch_statement();
         let body = self.generate_shatter_statement(ShatterType::STATIC);
         let tokens = quote! {
             {
                 #setup
                 if #check {
+                    #decoy
                     #body
                 }
             }
@@ -324,6 +353,11 @@ impl Shatter {
     pub fn post_compilation(&self, path: &Utf8PathBuf) {
         os::integrity_check_post_compilation(path, &self.integrity_checks);
     }
+
+    //Every decoy string planted in the file
+    pub fn decoys(&self) -> &[String] {
+        &self.decoys.injected
+    }
 }
 
 impl VisitMut for Shatter {
@@ -451,12 +485,17 @@ impl VisitMut for Shatter {
 }
 
 //TODO: Add configuration for conditional shatter injection
-pub fn shatter(input: &mut File) -> Shatter {
+pub fn shatter(input: &mut File, decoys: &DecoyConfig, integrity_hash: HashAlgorithm) -> Shatter {
     let mut state = Shatter {
         inside_unsafe_block: false,
         integrity_checks: Vec::new(),
+        integrity_hash,
+        decoys: Decoys::new(decoys),
     };
     Shatter::visit_file_mut(&mut state, input);
+    //Added after visiting, nothing in a static needs shattering
+    let statics = state.decoys.static_items();
+    input.items.extend(statics);
 
     state
 }
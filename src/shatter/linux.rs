@@ -42,8 +42,10 @@ pub fn generate_anti_debug_check() -> ShatterCondition {
     ShatterCondition { setup, check }
 }
 
-pub fn generate_integrity_check() -> ShatterCondition {
+pub fn generate_integrity_check(algorithm: crate::crypto::HashAlgorithm) -> (ShatterCondition, crate::shatter::IntegrityCheck) {
     let setup = quote! {};
     let check = quote! { false };
-    ShatterCondition { setup, check }
+    (ShatterCondition { setup, check }, crate::shatter::IntegrityCheck { check_type: crate::shatter::IntegrityCheckType::ALL, algorithm, hash: Vec::new(), salt: Vec::new() })
 }
+
+pub fn integrity_check_post_compilation(_path: &camino::Utf8PathBuf, _checks: &Vec<crate::shatter::IntegrityCheck>) {}
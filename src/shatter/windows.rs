@@ -23,7 +23,7 @@ use windows::Win32::System::LibraryLoader::GetModuleHandleA;
 use camino::Utf8PathBuf;
 use std::cmp;
 
-use crate::crypto::{self, hash};
+use crate::crypto::{self, hash, HashAlgorithm};
 use crate::shatter::{self, generate_unique_ident, IntegrityCheckType, IntegrityCheck, ShatterCondition};
 
 //Workaround to self obfuscate (since we can't add ourselves as a dependency)
@@ -112,10 +112,10 @@ pub fn integrity_check_post_compilation(path: &Utf8PathBuf, checks: &Vec<Integri
                  */
                 if let Some(_) = find_subsequence(&text_slice, &check.salt) {
                     let offset = find_subsequence(&data_slice, &check.hash).unwrap();
-                    let real_hash = crypto::hash::<crypto::Blake2b512>(&text_slice, Some(&check.salt));
+                    let real_hash = check.algorithm.hash(&text_slice, &check.salt);
                     eprintln!("Post Calculating against hash of len {}", text_slice.len());
 
-                    contents[data_start+offset..data_start+offset+64].copy_from_slice(&real_hash);
+                    contents[data_start+offset..data_start+offset+real_hash.len()].copy_from_slice(&real_hash);
 
                     eprintln!("Hash {:x?}", &check.hash);
                     eprintln!("REAL {real_hash:x?}");
@@ -129,18 +129,19 @@ pub fn integrity_check_post_compilation(path: &Utf8PathBuf, checks: &Vec<Integri
     fs::write(path, contents).unwrap();
 }
 
-pub fn generate_integrity_check() -> (ShatterCondition, IntegrityCheck) {
+pub fn generate_integrity_check(algorithm: HashAlgorithm) -> (ShatterCondition, IntegrityCheck) {
+    //Also the key for keyed algorithms, 32 bytes is as long as a BLAKE2s key can be
     let mut salt = [0u8; 32];
     OsRng.fill_bytes(&mut salt);
 
+    let hash_size = algorithm.output_size();
     //Magic random value to be replaced post compilation with the real hash
-    let mut hash = [0u8; 64];
+    let mut hash = vec![0u8; hash_size];
     OsRng.fill_bytes(&mut hash);
 
     let static_ident = generate_unique_ident();
     let salt_ident = generate_unique_ident();
-    //TODO: Remove these magic numbers
-    let hash_size = 64usize;
+    //TODO: Remove this magic number
     let salt_size = 32usize;
 
     let setup = quote! {
@@ -193,7 +194,7 @@ pub fn generate_integrity_check() -> (ShatterCondition, IntegrityCheck) {
 
                     let text_slice = &*::std::ptr::slice_from_raw_parts(addr, size as usize);
 
-                    calculated_hash = r2d2::crypto::hash::<r2d2::crypto::Blake2b512>(text_slice, Some(&#salt_ident));
+                    calculated_hash = #algorithm.hash(text_slice, &#salt_ident);
                     break;
                 }
             }
@@ -205,7 +206,8 @@ pub fn generate_integrity_check() -> (ShatterCondition, IntegrityCheck) {
         ShatterCondition { setup, check },
         IntegrityCheck {
             check_type: IntegrityCheckType::ALL,
-            hash: Vec::from(hash),
+            algorithm,
+            hash,
             salt: Vec::from(salt)
         }
     )
@@ -97,7 +97,8 @@ pub fn generate_rabbit_hole() -> Block {
         mov rbp, r10; \
         mov rcx, r11; \
         mov rdx, r12; \
-        jmp [rax + 8*rbx + rsi]; \
+        add rax, rsi; \
+        jmp [rax + 8*rbx]; \
         "
     );
 
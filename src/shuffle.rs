@@ -10,7 +10,7 @@ use crate as r2d2;
 
 const SHUFFLE_ATTR_NAME: &str = "shuffle";
 
-trait HasAttributes {
+pub(crate) trait HasAttributes {
     fn get_attrs(&mut self) -> Option<&mut Vec<Attribute>>;
     fn to_stmt(self) -> Stmt;
 }
@@ -0,0 +1,74 @@
+use std::mem::MaybeUninit;
+use zeroize::Zeroize;
+
+//Workaround to self obfuscate (since we can't add ourselves as a dependency)
+#[allow(unused_imports)]
+use crate as r2d2;
+
+/*
+ * Runtime support for #[r2d2::scrub_stack]
+ * Locals of a returning function stay on the stack until something else happens to overwrite
+ * them, decrypted data included
+ * Marked functions run their body through scrub_after, which zeroes the stack the body used
+ * once it's done, whichever way it exits
+ */
+
+//Used when neither the attribute nor the config give an amount
+pub const DEFAULT_SCRUB_BYTES: usize = 16 * 1024;
+
+/*
+ * Zeroes BYTES of stack right below the caller's frame
+ * Never inlined, so the area is a frame of its own, and zeroize writes are volatile so they
+ * can't be dropped for writing memory that's never read again
+ */
+#[inline(never)]
+pub fn scrub<const BYTES: usize>() {
+    let mut area = [MaybeUninit::<u8>::uninit(); BYTES];
+    area.zeroize();
+    std::hint::black_box(&area);
+}
+
+struct ScrubOnDrop<const BYTES: usize>;
+
+impl<const BYTES: usize> Drop for ScrubOnDrop<BYTES> {
+    fn drop(&mut self) {
+        scrub::<BYTES>();
+    }
+}
+
+//The body gets a frame below the caller's, which is the stack scrub goes over afterwards
+#[inline(never)]
+fn run<R, F: FnOnce() -> R>(body: F) -> R {
+    body()
+}
+
+//Runs body, then scrubs BYTES of the stack it used, also when it panics
+pub fn scrub_after<const BYTES: usize, R, F: FnOnce() -> R>(body: F) -> R {
+    let _scrub = ScrubOnDrop::<BYTES>;
+    run(body)
+}
+
+#[cfg(test)]
+mod scrub_stack_tests {
+    use crate::stack::*;
+
+    fn parse(input: &str) -> Result<u32, std::num::ParseIntError> {
+        scrub_after::<DEFAULT_SCRUB_BYTES, _, _>(|| -> Result<u32, std::num::ParseIntError> {
+            if input.is_empty() {
+                return Ok(0);
+            }
+            let value: u32 = input.parse()?;
+            Ok(value * 2)
+        })
+    }
+
+    #[test]
+    fn passes_results_through() {
+        assert_eq!(parse(""), Ok(0));
+        assert_eq!(parse("21"), Ok(42));
+        assert!(parse("nope").is_err());
+
+        let unwound = std::panic::catch_unwind(|| scrub_after::<64, (), _>(|| panic!("scrubbed")));
+        assert!(unwound.is_err());
+    }
+}
@@ -1,4 +1,12 @@
+use proc_macro2::TokenStream;
 use quote::*;
+use rand::distributions::Uniform;
+use rand::prelude::*;
+use rand::rngs::OsRng;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
 use syn::spanned::Spanned;
 use syn::visit_mut::*;
 use syn::*;
@@ -6,56 +14,928 @@ use syn::*;
 //Needed for the quote memory encryption routines to resolve
 use crate::crypto::*;
 use crate::parse::*;
+use crate::shatter::{generate_rabbit_hole, generate_unique_ident};
+use crate::shuffle::HasAttributes;
+use crate::strings::CachePolicy;
 //Workaround to self obfuscate (since we can't add ourselves as a dependency)
 #[allow(unused_imports)]
 use crate as r2d2;
 
+const SCHEME_ATTR_NAME: &str = "string_scheme";
+const SALT_SIZE: usize = 16;
+const MIN_KEY_SHARES: usize = 3;
+const MAX_KEY_SHARES: usize = 6;
+
+/*
+ * Per-file master secret, split across several statics emitted at the root of the file
+ * Literal sites only carry a salt, the real key is derived at runtime, so recovering a string
+ * means reversing the derivation rather than reading the bytes sitting next to the ciphertext
+ */
+pub(crate) struct MasterSecret {
+    pub(crate) shares: Vec<(proc_macro2::Ident, [u8; KEY_SHARE_SIZE])>,
+}
+
+impl MasterSecret {
+    fn new() -> Self {
+        let between = Uniform::from(MIN_KEY_SHARES..=MAX_KEY_SHARES);
+        let share_count: usize = between.sample(&mut OsRng);
+
+        let shares = (0..share_count)
+            .map(|_| {
+                let mut share = [0u8; KEY_SHARE_SIZE];
+                OsRng.fill_bytes(&mut share);
+                (generate_unique_ident(), share)
+            })
+            .collect();
+        MasterSecret { shares }
+    }
+
+    //Random shares that XOR back together into a given secret rather than a random one
+    pub(crate) fn split(secret: &[u8; KEY_SHARE_SIZE]) -> Self {
+        let mut output = MasterSecret::new();
+        let (last, rest) = output.shares.split_last_mut().unwrap();
+        last.1 = *secret;
+        for (_, share) in rest.iter() {
+            for (i, byte) in last.1.iter_mut().enumerate() {
+                *byte ^= share[i];
+            }
+        }
+        output
+    }
+
+    fn share_refs(&self) -> Vec<&[u8; KEY_SHARE_SIZE]> {
+        self.shares.iter().map(|(_, share)| share).collect()
+    }
+
+    pub(crate) fn to_items(&self) -> Vec<Item> {
+        self.shares
+            .iter()
+            .map(|(ident, share)| {
+                let tokens = quote! {
+                    #[allow(non_upper_case_globals)]
+                    static #ident: [u8; #KEY_SHARE_SIZE] = [#(#share),*];
+                };
+                syn::parse2::<Item>(tokens).unwrap()
+            })
+            .collect()
+    }
+}
+
+/*
+ * A way of protecting string literals
+ * Encryption happens at obfuscation time, decryption happens through a runtime function with the
+ * signature fn(&[&[u8; KEY_SHARE_SIZE]], &[u8], &[u8]) -> Option<Vec<u8>>
+ * Both sides get the split master secret and per-literal salt to derive their keys from
+ * Decryption returns None when the ciphertext fails authentication
+ */
+pub trait StringScheme {
+    fn encrypt(&self, shares: &[&[u8; KEY_SHARE_SIZE]], salt: &[u8], data: &[u8]) -> Vec<u8>;
+    //Path to the runtime function reversing encrypt
+    fn decrypt_fn(&self) -> TokenStream;
+}
+
+struct XChaCha20Poly1305Scheme;
+
+impl StringScheme for XChaCha20Poly1305Scheme {
+    fn encrypt(&self, shares: &[&[u8; KEY_SHARE_SIZE]], salt: &[u8], data: &[u8]) -> Vec<u8> {
+        encrypt_memory_derived::<XChaCha20Poly1305>(shares, salt, data).ciphertext
+    }
+
+    fn decrypt_fn(&self) -> TokenStream {
+        quote! { r2d2::crypto::try_decrypt_memory_derived::<r2d2::crypto::chacha20poly1305::XChaCha20Poly1305> }
+    }
+}
+
+struct Aes256GcmScheme;
+
+impl StringScheme for Aes256GcmScheme {
+    fn encrypt(&self, shares: &[&[u8; KEY_SHARE_SIZE]], salt: &[u8], data: &[u8]) -> Vec<u8> {
+        encrypt_memory_derived::<Aes256Gcm>(shares, salt, data).ciphertext
+    }
+
+    fn decrypt_fn(&self) -> TokenStream {
+        quote! { r2d2::crypto::try_decrypt_memory_derived::<r2d2::crypto::aes_gcm::Aes256Gcm> }
+    }
+}
+
+struct ChaCha20Scheme;
+
+impl StringScheme for ChaCha20Scheme {
+    fn encrypt(&self, shares: &[&[u8; KEY_SHARE_SIZE]], salt: &[u8], data: &[u8]) -> Vec<u8> {
+        apply_keystream_derived::<ChaCha20>(shares, salt, data)
+    }
+
+    fn decrypt_fn(&self) -> TokenStream {
+        //Nothing to authenticate, so this never fails
+        quote! {
+            |shares, salt, data| {
+                Some(r2d2::crypto::apply_keystream_derived::<r2d2::crypto::chacha20::ChaCha20>(
+                    shares, salt, data,
+                ))
+            }
+        }
+    }
+}
+
+struct RollingXorScheme;
+
+impl StringScheme for RollingXorScheme {
+    fn encrypt(&self, shares: &[&[u8; KEY_SHARE_SIZE]], salt: &[u8], data: &[u8]) -> Vec<u8> {
+        rolling_xor_derived(shares, salt, data)
+    }
+
+    fn decrypt_fn(&self) -> TokenStream {
+        quote! { |shares, salt, data| Some(r2d2::crypto::rolling_xor_derived(shares, salt, data)) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum StringSchemeKind {
+    //Authenticated, 192 bit nonces
+    XChaCha20Poly1305,
+    //Authenticated, 96 bit nonces
+    Aes256Gcm,
+    //Unauthenticated stream cipher, no tag overhead
+    ChaCha20,
+    //Barely better than plaintext, but nearly free to decrypt
+    RollingXor,
+}
+
+impl StringSchemeKind {
+    fn scheme(&self) -> &'static dyn StringScheme {
+        match self {
+            StringSchemeKind::XChaCha20Poly1305 => &XChaCha20Poly1305Scheme,
+            StringSchemeKind::Aes256Gcm => &Aes256GcmScheme,
+            StringSchemeKind::ChaCha20 => &ChaCha20Scheme,
+            StringSchemeKind::RollingXor => &RollingXorScheme,
+        }
+    }
+}
+
+impl Default for StringSchemeKind {
+    fn default() -> Self {
+        StringSchemeKind::XChaCha20Poly1305
+    }
+}
+
+impl FromStr for StringSchemeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "xchacha20poly1305" => Ok(StringSchemeKind::XChaCha20Poly1305),
+            "aes-gcm" => Ok(StringSchemeKind::Aes256Gcm),
+            "chacha20" => Ok(StringSchemeKind::ChaCha20),
+            "xor" => Ok(StringSchemeKind::RollingXor),
+            _ => Err(format!(
+                "Unknown string scheme \"{s}\", expected \"xchacha20poly1305\", \"aes-gcm\", \"chacha20\" or \"xor\""
+            )),
+        }
+    }
+}
+
+//What generated code does when a literal fails to decrypt, usually because it was patched
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+pub enum DecryptFailure {
+    //Panic with a generic message that doesn't mention the cipher
+    #[default]
+    Panic,
+    //Carry on with random text of the same length
+    Garbage,
+    //Kill the process without unwinding or printing anything
+    Abort,
+    //Jump into a shatter rabbit hole and crash somewhere unrelated
+    Shatter,
+}
+
+impl FromStr for DecryptFailure {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "panic" => Ok(DecryptFailure::Panic),
+            "garbage" => Ok(DecryptFailure::Garbage),
+            "abort" => Ok(DecryptFailure::Abort),
+            "shatter" => Ok(DecryptFailure::Shatter),
+            _ => Err(format!(
+                "Unknown decryption failure policy \"{s}\", expected \"panic\", \"garbage\", \"abort\" or \"shatter\""
+            )),
+        }
+    }
+}
+
+//A single encrypted literal, stored in the per-file string table
+struct TableEntry {
+    //Path to the runtime decryption function for the chosen scheme
+    decrypt_fn: TokenStream,
+    salt: [u8; SALT_SIZE],
+    ciphertext: Vec<u8>,
+    len: usize,
+}
+
+impl ToTokens for TableEntry {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let decrypt_fn = &self.decrypt_fn;
+        //Byte string literals keep the generated source a fraction of the size of u8 arrays
+        let salt = LitByteStr::new(&self.salt, proc_macro2::Span::call_site());
+        let ciphertext = LitByteStr::new(&self.ciphertext, proc_macro2::Span::call_site());
+        let len = self.len;
+
+        tokens.append_all(quote! {
+            r2d2::strings::StringEntry {
+                decrypt: #decrypt_fn,
+                salt: #salt,
+                ciphertext: #ciphertext,
+                len: #len,
+            }
+        });
+    }
+}
+
+impl ToTokens for CachePolicy {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        let output = match self {
+            CachePolicy::Forever => quote! { r2d2::strings::CachePolicy::Forever },
+            CachePolicy::PerThread => quote! { r2d2::strings::CachePolicy::PerThread },
+            CachePolicy::EveryN(count) => quote! { r2d2::strings::CachePolicy::EveryN(#count) },
+        };
+        tokens.append_all(output);
+    }
+}
+
+//How a literal site gets at its decrypted value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lookup {
+    //Temporary &str, only lives until the end of the enclosing statement
+    Borrowed,
+    //Owned string for let bindings
+    Owned,
+    //Temporary &[u8], only lives until the end of the enclosing statement
+    BorrowedBytes,
+    //Owned byte buffer for let bindings
+    OwnedBytes,
+    //Temporary &[u8; N] for include_bytes!, lives as long as the binding in a let
+    BorrowedArray,
+    //Owned [u8; N] buffer for hidden bindings behind an explicit &[u8; N]
+    OwnedArray,
+}
+
+//Literal site lookup into the per-file string table
 struct MemEncCtx {
-    ctx: MemoryEncryptionCtx<XChaCha20Poly1305>,
-    needs_owned_str: bool,
+    //Path to the string table, relative to the module the literal lives in
+    table: TokenStream,
+    index: usize,
+    lookup: Lookup,
+    cached: bool,
+    //Plaintext length, which arrays carry in their type
+    len: usize,
 }
 
 impl ToTokens for MemEncCtx {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        let key = &self.ctx.key;
-        let nonce = &self.ctx.nonce;
-        let ciphertext = &self.ctx.ciphertext;
-        let output: proc_macro2::TokenStream;
+        let table = &self.table;
+        let index = self.index;
+        let len = self.len;
 
-        if self.needs_owned_str {
+        let value = if self.cached {
+            quote! { r2d2::strings::get_cached(&#table, #index) }
+        } else {
+            quote! { r2d2::strings::get(&#table, #index) }
+        };
+
+        let output = match self.lookup {
+            Lookup::Borrowed => quote! {
+                #value.as_str()
+            },
             /*
              * let x = "foobar";
              * println!("{}", x);
              *
              * This snippet requires special handling since the temporary decrypted string goes out
              * of scope after decryption, so we need to return a string object rather than a &str
-             * This could theoretically still have issues with explicit typing, but we'll cross
-             * that bridge when we get there
+             * That object is a SecretString so the plaintext is wiped once the binding goes away
+             * Explicitly typed references borrow from a hidden binding holding this object
              */
-            output = quote! {
-                let result = r2d2::crypto::decrypt_memory::<r2d2::crypto::chacha20poly1305::XChaCha20Poly1305>(r2d2::crypto::MemoryEncryptionCtx {
-                    key: (r2d2::generic_array::arr![u8; #(#key),*]) as r2d2::crypto::aead::Key::<r2d2::crypto::chacha20poly1305::XChaCha20Poly1305>,
-                    nonce: (r2d2::generic_array::arr![u8; #(#nonce),*]) as r2d2::crypto::aead::Nonce::<r2d2::crypto::chacha20poly1305::XChaCha20Poly1305>,
-                    ciphertext: ::std::vec![#(#ciphertext),*],
-                });
-                ::std::string::String::from_utf8(result).unwrap()
+            Lookup::Owned => quote! {
+                #value
+            },
+            //Byte literals don't have to be valid UTF-8, so they never go through SecretString
+            Lookup::BorrowedBytes => quote! {
+                r2d2::strings::get_bytes(&#table, #index).as_slice()
+            },
+            Lookup::OwnedBytes => quote! {
+                r2d2::strings::get_bytes(&#table, #index)
+            },
+            /*
+             * include_bytes! evaluates to &'static [u8; N], and callers can depend on the length
+             * being part of the type, so these don't decay into slices or vectors
+             * Borrowing through &* extends the temporary to the end of the block in a let
+             */
+            Lookup::BorrowedArray => quote! {
+                &*r2d2::strings::get_byte_array::<#len>(&#table, #index)
+            },
+            Lookup::OwnedArray => quote! {
+                r2d2::strings::get_byte_array::<#len>(&#table, #index)
+            },
+        };
+
+        tokens.append_all(output);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    //const and static initializers have to be constant expressions
+    ConstItem,
+    //Function and method arguments may borrow past the end of a temporary
+    CallArgument,
+    //Patterns that couldn't be moved into a match guard
+    MatchPattern,
+    //Macro bodies other than simple format strings are opaque tokens
+    UnsupportedMacro,
+    //let x: &T = "..." where &T can't be borrowed from a decrypted value
+    ExplicitReferenceType,
+    //Attribute arguments are never expressions
+    Attribute,
+    //include_str!/include_bytes! whose file couldn't be read at obfuscation time
+    UnreadableInclude,
+    //let initializers other than a bare literal are left alone, see visit_local_mut
+    LetInitializer,
+    //Anywhere else the pass doesn't reach
+    UnsupportedExpression,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::ConstItem => write!(f, "const item"),
+            SkipReason::CallArgument => write!(f, "call argument"),
+            SkipReason::MatchPattern => write!(f, "match pattern"),
+            SkipReason::UnsupportedMacro => write!(f, "unsupported macro"),
+            SkipReason::ExplicitReferenceType => write!(f, "explicit reference type"),
+            SkipReason::Attribute => write!(f, "attribute"),
+            SkipReason::UnreadableInclude => write!(f, "unreadable include"),
+            SkipReason::LetInitializer => write!(f, "let initializer"),
+            SkipReason::UnsupportedExpression => write!(f, "unsupported expression"),
+        }
+    }
+}
+
+//A string literal the pass had to leave as plaintext
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedLiteral {
+    pub line: usize,
+    pub column: usize,
+    pub reason: SkipReason,
+}
+
+fn pat_str_literal(pat: &Pat) -> Option<LitStr> {
+    if let Pat::Lit(PatLit { expr, .. }) = pat {
+        if let Expr::Lit(ExprLit {
+            lit: Lit::Str(s), ..
+        }) = &**expr
+        {
+            return Some(s.to_owned());
+        }
+    }
+    None
+}
+
+//Replaces string literal patterns with fresh bindings, remembering what each binding must equal
+struct PatLiteralReplace {
+    bindings: Vec<(proc_macro2::Ident, LitStr)>,
+}
+
+impl VisitMut for PatLiteralReplace {
+    fn visit_pat_mut(&mut self, node: &mut Pat) {
+        if let Some(lit) = pat_str_literal(node) {
+            let ident = generate_unique_ident();
+            *node = Pat::Ident(PatIdent {
+                attrs: Vec::new(),
+                by_ref: None,
+                mutability: None,
+                ident: ident.to_owned(),
+                subpat: None,
+            });
+            self.bindings.push((ident, lit));
+            return;
+        }
+        visit_mut::visit_pat_mut(self, node);
+    }
+}
+
+/*
+ * "abc" => {
+ *     let mut buf = StackString::<3>::zeroed();
+ *     buf.set(2, black_box(0x41) ^ 0x22);
+ *     buf.set(0, black_box(0x5E).wrapping_add(0x03));
+ *     buf.set(1, black_box(0x73).wrapping_sub(0x11));
+ *     buf
+ * }.as_str()
+ *
+ * Stack string encoding for short literals, built from immediate writes instead of a table lookup
+ * black_box keeps the compiler from folding the masks back into plaintext immediates
+ */
+fn stack_literal(data: &[u8], lookup: Lookup) -> Option<ExprBlock> {
+    let buffer_type = match lookup {
+        Lookup::Borrowed | Lookup::Owned => quote! { StackString },
+        Lookup::BorrowedBytes | Lookup::OwnedBytes => quote! { StackBytes },
+        //Stack buffers deref to slices, arrays go through the table to keep their type
+        Lookup::BorrowedArray | Lookup::OwnedArray => return None,
+    };
+
+    let buf = generate_unique_ident();
+    let len = data.len();
+    let mut order: Vec<usize> = (0..len).collect();
+    order.shuffle(&mut OsRng);
+
+    let writes = order.into_iter().map(|index| {
+        let byte = data[index];
+        let mask: u8 = OsRng.gen();
+        match OsRng.gen_range(0..3) {
+            0 => {
+                let masked = byte ^ mask;
+                quote! { #buf.set(#index, ::std::hint::black_box(#masked) ^ #mask); }
+            }
+            1 => {
+                let masked = byte.wrapping_sub(mask);
+                quote! { #buf.set(#index, ::std::hint::black_box(#masked).wrapping_add(#mask)); }
+            }
+            _ => {
+                let masked = byte.wrapping_add(mask);
+                quote! { #buf.set(#index, ::std::hint::black_box(#masked).wrapping_sub(#mask)); }
+            }
+        }
+    });
+
+    let construct = quote! {
+        {
+            let mut #buf = r2d2::strings::#buffer_type::<#len>::zeroed();
+            #(#writes)*
+            #buf
+        }
+    };
+    let value = match lookup {
+        Lookup::Borrowed => quote! { #construct.as_str() },
+        Lookup::BorrowedBytes => quote! { #construct.as_bytes() },
+        _ => construct,
+    };
+    let output = quote! {
+        {
+            #value
+        }
+    };
+    Some(syn::parse2::<ExprBlock>(output).unwrap())
+}
+
+/*
+ * Finds string literals nested under an or-pattern
+ * Every alternative has to bind the same names, so those can't be swapped for fresh bindings
+ */
+struct OrPatLiteralFinder {
+    in_or: bool,
+    found: bool,
+}
+
+impl<'ast> syn::visit::Visit<'ast> for OrPatLiteralFinder {
+    fn visit_pat(&mut self, node: &'ast Pat) {
+        if self.in_or && pat_str_literal(node).is_some() {
+            self.found = true;
+        }
+        syn::visit::visit_pat(self, node);
+    }
+
+    fn visit_pat_or(&mut self, node: &'ast PatOr) {
+        let prev = self.in_or;
+        self.in_or = true;
+        syn::visit::visit_pat_or(self, node);
+        self.in_or = prev;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StrEncConfig {
+    //Cache decrypted literals per site instead of decrypting on every evaluation
+    pub cache_policy: Option<CachePolicy>,
+    //Scheme used unless overridden with a #[string_scheme = "..."] attribute
+    pub scheme: StringSchemeKind,
+    //Literals up to this many bytes are built as stack strings instead, 0 disables
+    pub stack_threshold: usize,
+    pub on_failure: DecryptFailure,
+    //Also encrypt messages passed to panic!, the assert family and expect()
+    pub panic_messages: bool,
+}
+
+//Panicking macros taking the same arguments as format!
+const PANIC_FORMAT_MACROS: [&str; 4] = ["panic", "unreachable", "todo", "unimplemented"];
+
+struct StrReplace<'a> {
+    config: &'a StrEncConfig,
+    //Directory of the original source file, include paths are relative to it
+    source_dir: Option<&'a Path>,
+    secret: MasterSecret,
+    //Number of inline modules between the current node and the root of the file
+    mod_depth: usize,
+    table_ident: proc_macro2::Ident,
+    entries: Vec<TableEntry>,
+    //Innermost #[string_scheme] attribute applies, falling back to the configured scheme
+    scheme_overrides: Vec<StringSchemeKind>,
+    //Line and column of every literal replaced so far
+    encrypted: Vec<(usize, usize)>,
+    skipped: Vec<SkippedLiteral>,
+}
+
+impl<'a> StrReplace<'a> {
+    fn new(config: &'a StrEncConfig, source_path: Option<&'a Path>) -> Self {
+        StrReplace {
+            config,
+            source_dir: source_path.and_then(Path::parent),
+            secret: MasterSecret::new(),
+            mod_depth: 0,
+            table_ident: generate_unique_ident(),
+            entries: Vec::new(),
+            scheme_overrides: Vec::new(),
+            encrypted: Vec::new(),
+            skipped: Vec::new(),
+        }
+    }
+
+    //span is where the literal sits in the original source, only used for reporting
+    fn encrypt_literal(
+        &mut self,
+        span: proc_macro2::Span,
+        data: &[u8],
+        lookup: Lookup,
+    ) -> ExprBlock {
+        let start = span.start();
+        self.encrypted.push((start.line, start.column));
+
+        if !data.is_empty() && data.len() <= self.config.stack_threshold {
+            if let Some(output) = stack_literal(data, lookup) {
+                return output;
+            }
+        }
+
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+
+        let scheme = self
+            .scheme_overrides
+            .last()
+            .unwrap_or(&self.config.scheme)
+            .scheme();
+        let ciphertext = scheme.encrypt(&self.secret.share_refs(), &salt, data);
+
+        self.entries.push(TableEntry {
+            decrypt_fn: scheme.decrypt_fn(),
+            salt,
+            ciphertext,
+            len: data.len(),
+        });
+
+        //The table sits at the root of the file, so walk back up out of any inline modules
+        let table_ident = &self.table_ident;
+        let supers = std::iter::repeat(quote! { super:: }).take(self.mod_depth);
+
+        let mem_ctx = MemEncCtx {
+            table: quote! { #(#supers)* #table_ident },
+            index: self.entries.len() - 1,
+            lookup,
+            cached: self.config.cache_policy.is_some(),
+            len: data.len(),
+        };
+        let output = quote! {
+            {
+                #mem_ctx
+            }
+        };
+        syn::parse2::<ExprBlock>(output).unwrap()
+    }
+
+    fn skip(&mut self, span: proc_macro2::Span, reason: SkipReason) {
+        let start = span.start();
+        self.skipped.push(SkippedLiteral {
+            line: start.line,
+            column: start.column,
+            reason,
+        });
+    }
+
+    /*
+     * include_str!("query.sql") / include_bytes!("cert.der")
+     *
+     * Include paths are relative to the original source file rather than the copy being
+     * obfuscated, so the file is read from there and its contents treated like any other literal
+     * Computed paths like concat!(env!("OUT_DIR"), ...) are left for the compiler to resolve
+     * Returns the contents, whether they came from include_bytes!, and where the path sits
+     */
+    fn read_include(&mut self, node: &ExprMacro) -> Option<(Vec<u8>, bool, proc_macro2::Span)> {
+        let is_bytes = match node.mac.path.segments.last() {
+            Some(segment) if segment.ident == "include_str" => false,
+            Some(segment) if segment.ident == "include_bytes" => true,
+            _ => return None,
+        };
+        let path = node.mac.parse_body::<LitStr>().ok()?;
+
+        let contents = self
+            .source_dir
+            .and_then(|dir| fs::read(dir.join(path.value())).ok())
+            .filter(|contents| is_bytes || std::str::from_utf8(contents).is_ok());
+        if contents.is_none() {
+            self.skip(path.span(), SkipReason::UnreadableInclude);
+        }
+        contents.map(|contents| (contents, is_bytes, path.span()))
+    }
+
+    /*
+     * let x: &str = "foobar";
+     *
+     * Nothing would own the decrypted value, so a hidden binding is added in front to own it
+     * let var_1 = <decrypt>;
+     * let x: &str = &var_1;
+     *
+     * &'static references can't borrow from a local, and leaking the plaintext would keep it in
+     * memory for the life of the process, so those stay plaintext and are reported as skipped
+     * Returns the hidden binding statement, if one is needed
+     */
+    fn encrypt_typed_local(&mut self, node: &mut Local) -> Option<Stmt> {
+        let reference = match &node.pat {
+            Pat::Type(PatType { ty, .. }) => match &**ty {
+                Type::Reference(reference) => reference.to_owned(),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let init = node.init.as_mut()?;
+        let is_include = matches!(&*init.1, Expr::Macro(_));
+        let (data, is_bytes, span) = match &*init.1 {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(s), ..
+            }) => (s.value().into_bytes(), false, s.span()),
+            Expr::Lit(ExprLit {
+                lit: Lit::ByteStr(s),
+                ..
+            }) => (s.value(), true, s.span()),
+            Expr::Macro(mac) => self.read_include(mac)?,
+            _ => return None,
+        };
+
+        //Named lifetimes, 'static included, may outlive the hidden binding
+        if reference.lifetime.is_some() {
+            self.skip(span, SkipReason::ExplicitReferenceType);
+            return None;
+        }
+        let is_u8 = |ty: &Type| matches!(ty, Type::Path(path) if path.path.is_ident("u8"));
+        let supported = reference.mutability.is_none()
+            && match &*reference.elem {
+                Type::Path(path) => !is_bytes && path.qself.is_none() && path.path.is_ident("str"),
+                Type::Slice(slice) => is_bytes && is_u8(&slice.elem),
+                //Only include_bytes! is rewritten into arrays, see MemEncCtx
+                Type::Array(array) => is_include && is_bytes && is_u8(&array.elem),
+                _ => false,
+            };
+        if !supported {
+            self.skip(span, SkipReason::ExplicitReferenceType);
+            return None;
+        }
+
+        let is_array = matches!(&*reference.elem, Type::Array(_));
+        let lookup = match (is_bytes, is_array) {
+            (false, _) => Lookup::Owned,
+            (true, false) => Lookup::OwnedBytes,
+            (true, true) => Lookup::OwnedArray,
+        };
+        let value = self.encrypt_literal(span, &data, lookup);
+
+        let hidden = generate_unique_ident();
+        *init.1 = syn::parse2::<Expr>(quote! { &#hidden }).unwrap();
+        Some(syn::parse2::<Stmt>(quote! { let #hidden = #value; }).unwrap())
+    }
+
+    //Key shares, string table, and caches if enabled, all destined for the root of the file
+    fn table_items(&self) -> Vec<Item> {
+        let mut items = self.secret.to_items();
+
+        let table_ident = &self.table_ident;
+        let share_idents = self.secret.shares.iter().map(|(ident, _)| ident);
+        let entries = &self.entries;
+
+        let caches = match self.config.cache_policy {
+            Some(policy) => {
+                let caches_ident = generate_unique_ident();
+                let count = self.entries.len();
+                let policies = std::iter::repeat(policy).take(count);
+                let tokens = quote! {
+                    #[allow(non_upper_case_globals)]
+                    static #caches_ident: [r2d2::strings::StringCache; #count] = [
+                        #(r2d2::strings::StringCache::new(#policies)),*
+                    ];
+                };
+                items.push(syn::parse2::<Item>(tokens).unwrap());
+                quote! { ::std::option::Option::Some(&#caches_ident) }
+            }
+            None => quote! { ::std::option::Option::None },
+        };
+
+        let on_failure = match self.config.on_failure {
+            DecryptFailure::Panic => quote! { r2d2::strings::fail_panic },
+            DecryptFailure::Garbage => quote! { r2d2::strings::fail_garbage },
+            DecryptFailure::Abort => quote! { r2d2::strings::fail_abort },
+            DecryptFailure::Shatter => {
+                let fail_ident = generate_unique_ident();
+                let rabbit_hole = generate_rabbit_hole();
+                let tokens = quote! {
+                    fn #fail_ident(_len: usize) -> ::std::vec::Vec<u8> {
+                        unsafe #rabbit_hole
+                        //Rabbit holes aren't guaranteed to crash
+                        ::std::process::abort()
+                    }
+                };
+                items.push(syn::parse2::<Item>(tokens).unwrap());
+                quote! { #fail_ident }
+            }
+        };
+
+        let tokens = quote! {
+            #[allow(non_upper_case_globals)]
+            static #table_ident: r2d2::strings::StringTable = r2d2::strings::StringTable {
+                shares: &[#(&#share_idents),*],
+                entries: &[#(#entries),*],
+                caches: #caches,
+                on_failure: #on_failure,
+            };
+        };
+        items.push(syn::parse2::<Item>(tokens).unwrap());
+        items
+    }
+
+    /*
+     * Literal patterns can't hold a decryption call, so they become bindings checked in the guard
+     *
+     * match cmd { "unlock" => ..., "a" | "b" => ..., _ => ... }
+     * match cmd {
+     *     var_1 if ct_eq(var_1, <decrypt "unlock">) => ...,
+     *     var_2 if (ct_eq(var_2, <decrypt "a">) || ct_eq(var_2, <decrypt "b">)) => ...,
+     *     _ => ...
+     * }
+     *
+     * Guarded arms never count towards exhaustiveness, but a match on strings always needs a
+     * catch-all arm anyway, and the arms stay in their original order
+     * Any existing guard is kept, and only runs once the literals have matched
+     */
+    fn encrypt_arm_pattern(&mut self, node: &mut Arm) {
+        let mut checks: Vec<TokenStream> = Vec::new();
+
+        if let Pat::Or(or) = &node.pat {
+            let literals: Option<Vec<LitStr>> = or.cases.iter().map(pat_str_literal).collect();
+            if let Some(literals) = literals {
+                let ident = generate_unique_ident();
+                let alternatives: Vec<TokenStream> = literals
+                    .iter()
+                    .map(|lit| {
+                        let value = self.encrypt_literal(
+                            lit.span(),
+                            lit.value().as_bytes(),
+                            Lookup::Borrowed,
+                        );
+                        quote! { r2d2::strings::ct_eq(#ident, #value) }
+                    })
+                    .collect();
+                checks.push(quote! { (#(#alternatives)||*) });
+                node.pat = syn::parse2::<Pat>(quote! { #ident }).unwrap();
+            }
+        }
+
+        if checks.is_empty() {
+            let mut finder = OrPatLiteralFinder {
+                in_or: false,
+                found: false,
+            };
+            syn::visit::Visit::visit_pat(&mut finder, &node.pat);
+            if finder.found {
+                return;
+            }
+
+            let mut replacer = PatLiteralReplace {
+                bindings: Vec::new(),
             };
+            replacer.visit_pat_mut(&mut node.pat);
+            for (ident, lit) in replacer.bindings {
+                let value =
+                    self.encrypt_literal(lit.span(), lit.value().as_bytes(), Lookup::Borrowed);
+                checks.push(quote! { r2d2::strings::ct_eq(#ident, #value) });
+            }
+        }
+
+        if checks.is_empty() {
+            return;
+        }
+
+        let span = node.pat.span();
+        if let Some((_, guard)) = node.guard.take() {
+            checks.push(quote! { (#guard) });
+        }
+        let condition = syn::parse2::<Expr>(quote! { #(#checks)&&* }).unwrap();
+        node.guard = Some((Token![if](span), Box::new(condition)));
+    }
+
+    /*
+     * Arguments of a format!-style macro
+     * A format string with no placeholders becomes ("{}", <decrypt>), others only get their
+     * arguments replaced
+     */
+    fn encrypt_format_args(&mut self, parsed: &mut FormatArgs) {
+        let mut can_encrypt = true;
+        if let Lit::Str(s) = &parsed.format_string.lit {
+            if s.value().contains("{") {
+                //Don't mess with format strings that aren't trivial
+                can_encrypt = false;
+            }
         } else {
-            output = quote! {
-                let result = r2d2::crypto::decrypt_memory::<r2d2::crypto::chacha20poly1305::XChaCha20Poly1305>(r2d2::crypto::MemoryEncryptionCtx {
-                    key: (r2d2::generic_array::arr![u8; #(#key),*]) as r2d2::crypto::aead::Key::<r2d2::crypto::chacha20poly1305::XChaCha20Poly1305>,
-                    nonce: (r2d2::generic_array::arr![u8; #(#nonce),*]) as r2d2::crypto::aead::Nonce::<r2d2::crypto::chacha20poly1305::XChaCha20Poly1305>,
-                    ciphertext: ::std::vec![#(#ciphertext),*],
-                });
-                ::std::string::String::from_utf8(result).unwrap().as_str()
+            panic!("Format string is not a string literal!");
+        }
+
+        if parsed.positional_args.is_empty() && parsed.named_args.is_empty() && can_encrypt {
+            //Change the string literal to ("{}", "str") to allow block expression replacement
+            let span = parsed.format_string.span();
+
+            //Store the old value as an arg
+            parsed
+                .positional_args
+                .push(Expr::Lit(parsed.format_string.to_owned()));
+
+            //Replace the format string with a trivial one
+            parsed.format_string = ExprLit {
+                attrs: Vec::new(),
+                lit: Lit::Str(LitStr::new("{}", span)),
             };
+
+            Self::visit_expr_mut(self, &mut parsed.positional_args[0]);
+        } else {
+            parsed
+                .positional_args
+                .iter_mut()
+                .for_each(|e| Self::visit_expr_mut(self, e));
         }
+    }
 
-        tokens.append_all(output);
+    /*
+     * x.expect("msg"), the message only has to live until expect returns
+     * Follows method chains like x.expect("a").y().expect("b") down through the receivers
+     */
+    fn encrypt_expect_messages(&mut self, node: &mut ExprMethodCall) {
+        if node.method == "expect" && node.args.len() == 1 {
+            if let Some(Expr::Lit(ExprLit {
+                lit: Lit::Str(s), ..
+            })) = node.args.first()
+            {
+                let output = self.encrypt_literal(s.span(), s.value().as_bytes(), Lookup::Borrowed);
+                node.args[0] = Expr::Block(output);
+            }
+        }
+        if let Expr::MethodCall(receiver) = &mut *node.receiver {
+            self.encrypt_expect_messages(receiver);
+        }
+    }
+
+    /*
+     * Strip a #[string_scheme] attribute, returning whether it pushed an override
+     * A malformed one pushes nothing, and gives back the message of a compile_error! for the
+     * caller to place next to what it was on, see scheme_error
+     */
+    fn push_scheme_override(
+        &mut self,
+        attrs: &mut Vec<Attribute>,
+    ) -> std::result::Result<bool, String> {
+        let position = attrs
+            .iter()
+            .position(|attr| attr.path.is_ident(SCHEME_ATTR_NAME));
+        if let Some(position) = position {
+            let attr = attrs.remove(position);
+            let scheme = match attr.parse_meta() {
+                Ok(Meta::NameValue(MetaNameValue {
+                    lit: Lit::Str(name),
+                    ..
+                })) => name.value().parse::<StringSchemeKind>(),
+                _ => Err(format!(
+                    "Expected #[{SCHEME_ATTR_NAME} = \"...\"], found #[{}{}]",
+                    attr.path.to_token_stream(),
+                    attr.tokens
+                )),
+            };
+            let scheme = scheme?;
+            self.scheme_overrides.push(scheme);
+            return Ok(true);
+        }
+        Ok(false)
     }
 }
 
-struct StrReplace;
+//Malformed #[string_scheme] attributes build as an error rather than stopping the obfuscator
+fn scheme_error(message: &str) -> Stmt {
+    parse_quote!(compile_error!(#message);)
+}
 
 /*
  * The choice of Self::visit_*_mut vs visit_mut::visit_*_mut is important here
@@ -64,61 +944,50 @@ struct StrReplace;
  *
  * NOTE: DO NOT MODIFY WITHOUT TESTING AND VERIFICATION
  */
-impl VisitMut for StrReplace {
+impl<'a> VisitMut for StrReplace<'a> {
     fn visit_macro_mut(&mut self, node: &mut Macro) {
         let macro_path = node
             .path
             .get_ident()
             .map(|ident| ident.to_string())
             .unwrap_or_default();
-        let mut can_encrypt = match macro_path.as_str() {
+        let panic_messages = self.config.panic_messages;
+        let is_format = match macro_path.as_str() {
             "println" => true,
             "eprintln" => true,
             "format" => true,
             "concat" => true,
-            _ => false,
+            name => panic_messages && PANIC_FORMAT_MACROS.contains(&name),
         };
 
-        //Don't even process macros we don't understand
-        if !can_encrypt {
-            visit_mut::visit_macro_mut(self, node);
-            return;
-        }
-
-        if let Ok(mut parsed) = node.parse_body::<FormatArgs>() {
-            if let Lit::Str(s) = &parsed.format_string.lit {
-                if s.value().contains("{") {
-                    //Don't mess with format strings that aren't trivial
-                    can_encrypt = false;
-                }
-            } else {
-                panic!("Format string is not a string literal!");
+        if is_format {
+            if let Ok(mut parsed) = node.parse_body::<FormatArgs>() {
+                self.encrypt_format_args(&mut parsed);
+                node.tokens = parsed.to_token_stream();
             }
-
-            if parsed.positional_args.is_empty() && parsed.named_args.is_empty() && can_encrypt {
-                //Change the string literal to ("{}", "str") to allow block expression replacement
-                let span = parsed.format_string.span();
-
-                //Store the old value as an arg
-                parsed
-                    .positional_args
-                    .push(Expr::Lit(parsed.format_string.to_owned()));
-
-                //Replace the format string with a trivial one
-                parsed.format_string = ExprLit {
-                    attrs: Vec::new(),
-                    lit: Lit::Str(LitStr::new("{}", span)),
-                };
-
-                Self::visit_expr_mut(self, &mut parsed.positional_args[0]);
-            } else {
-                parsed
-                    .positional_args
-                    .iter_mut()
-                    .for_each(|mut e| Self::visit_expr_mut(self, &mut e));
+        } else if panic_messages {
+            //Only the messages, conditions are left alone like any other macro body
+            match macro_path.as_str() {
+                "assert" | "debug_assert" => {
+                    if let Ok(mut parsed) = node.parse_body::<AssertArgs>() {
+                        if let Some(message) = &mut parsed.message {
+                            self.encrypt_format_args(message);
+                            node.tokens = parsed.to_token_stream();
+                        }
+                    }
+                }
+                "assert_eq" | "assert_ne" | "debug_assert_eq" | "debug_assert_ne" => {
+                    if let Ok(mut parsed) = node.parse_body::<AssertCmpArgs>() {
+                        if let Some(message) = &mut parsed.message {
+                            self.encrypt_format_args(message);
+                            node.tokens = parsed.to_token_stream();
+                        }
+                    }
+                }
+                _ => (),
             }
-            node.tokens = parsed.to_token_stream();
         }
+
         // Delegate to the default impl to visit nested macros.
         visit_mut::visit_macro_mut(self, node);
     }
@@ -139,34 +1008,34 @@ impl VisitMut for StrReplace {
         };
 
         if must_skip {
+            if let Expr::MethodCall(call) = node {
+                if self.config.panic_messages {
+                    self.encrypt_expect_messages(call);
+                }
+            }
             return;
         }
 
+        if let Expr::Macro(mac) = &node {
+            if let Some((data, is_bytes, span)) = self.read_include(mac) {
+                let lookup = if is_bytes {
+                    Lookup::BorrowedArray
+                } else {
+                    Lookup::Borrowed
+                };
+                let output = self.encrypt_literal(span, &data, lookup);
+                *node = Expr::Block(output);
+                return;
+            }
+        }
+
         if let Expr::Lit(expr) = &node {
             if let Lit::Str(s) = &expr.lit {
-                let mem_ctx = MemEncCtx {
-                    ctx: encrypt_memory::<XChaCha20Poly1305>(s.value().as_bytes()),
-                    needs_owned_str: false,
-                };
-                let output = quote! {
-                    {
-                        #mem_ctx
-                    }
-                };
-                let output = syn::parse2::<ExprBlock>(output).unwrap();
+                let output = self.encrypt_literal(s.span(), s.value().as_bytes(), Lookup::Borrowed);
                 *node = Expr::Block(output);
                 return;
             } else if let Lit::ByteStr(s) = &expr.lit {
-                let mem_ctx = MemEncCtx {
-                    ctx: encrypt_memory::<XChaCha20Poly1305>(&s.value()),
-                    needs_owned_str: false,
-                };
-                let output = quote! {
-                    {
-                        #mem_ctx
-                    }
-                };
-                let output = syn::parse2::<ExprBlock>(output).unwrap();
+                let output = self.encrypt_literal(s.span(), &s.value(), Lookup::BorrowedBytes);
                 *node = Expr::Block(output);
                 return;
             }
@@ -176,10 +1045,69 @@ impl VisitMut for StrReplace {
     }
 
     fn visit_arm_mut(&mut self, node: &mut Arm) {
-        //Don't visit patterns, those string literals can't be replaced
+        //Don't visit patterns, those string literals can't be replaced, only moved into guards
+        self.encrypt_arm_pattern(node);
         Self::visit_expr_mut(self, &mut node.body);
     }
 
+    //Errors go in after visiting, so their message isn't encrypted along with the body
+    fn visit_item_fn_mut(&mut self, node: &mut ItemFn) {
+        let pushed = self.push_scheme_override(&mut node.attrs);
+        visit_mut::visit_item_fn_mut(self, node);
+        match pushed {
+            Ok(true) => drop(self.scheme_overrides.pop()),
+            Ok(false) => {}
+            Err(message) => node.block.stmts.insert(0, scheme_error(&message)),
+        }
+    }
+
+    fn visit_impl_item_method_mut(&mut self, node: &mut ImplItemMethod) {
+        let pushed = self.push_scheme_override(&mut node.attrs);
+        visit_mut::visit_impl_item_method_mut(self, node);
+        match pushed {
+            Ok(true) => drop(self.scheme_overrides.pop()),
+            Ok(false) => {}
+            Err(message) => node.block.stmts.insert(0, scheme_error(&message)),
+        }
+    }
+
+    fn visit_block_mut(&mut self, node: &mut Block) {
+        let mut stmts: Vec<Stmt> = Vec::with_capacity(node.stmts.len());
+
+        for mut stmt in node.stmts.drain(..) {
+            let pushed = match stmt.get_attrs() {
+                Some(attrs) => match self.push_scheme_override(attrs) {
+                    Ok(pushed) => pushed,
+                    Err(message) => {
+                        stmts.push(scheme_error(&message));
+                        false
+                    }
+                },
+                None => false,
+            };
+            if let Stmt::Local(local) = &mut stmt {
+                //Hidden bindings have to come before the statement borrowing from them
+                if let Some(hidden) = self.encrypt_typed_local(local) {
+                    stmts.push(hidden);
+                }
+            }
+            visit_mut::visit_stmt_mut(self, &mut stmt);
+            if pushed {
+                self.scheme_overrides.pop();
+            }
+            stmts.push(stmt);
+        }
+
+        node.stmts = stmts;
+    }
+
+    fn visit_item_mod_mut(&mut self, node: &mut ItemMod) {
+        //Key share paths are relative, so track how deep into inline modules we are
+        self.mod_depth += 1;
+        visit_mut::visit_item_mod_mut(self, node);
+        self.mod_depth -= 1;
+    }
+
     fn visit_item_const_mut(&mut self, _node: &mut ItemConst) {
         /*
          * Skip all constant expressions since we can't decrypt those
@@ -187,7 +1115,22 @@ impl VisitMut for StrReplace {
          */
     }
 
+    fn visit_item_static_mut(&mut self, _node: &mut ItemStatic) {
+        /*
+         * Static initializers are constant expressions too
+         * Function intentionally left blank
+         */
+    }
+
     fn visit_local_mut(&mut self, node: &mut Local) {
+        if let Some((_, init)) = &mut node.init {
+            if let Expr::MethodCall(call) = &mut **init {
+                if self.config.panic_messages {
+                    self.encrypt_expect_messages(call);
+                }
+            }
+        }
+
         if let Some(init) = &node.init {
             if let Expr::Lit(expr) = &*init.1 {
                 if let Lit::Str(s) = &expr.lit {
@@ -196,6 +1139,7 @@ impl VisitMut for StrReplace {
                      * This is fine for string literals due to static lifetime
                      * Decryption doesn't have a static lifetime, so an explicit reference storage
                      * will run into object lifetime issues
+                     * Supported reference types were already rewritten when visiting the block
                      */
                     if let Pat::Type(ty) = &node.pat {
                         if let Type::Reference(_) = *ty.ty {
@@ -203,24 +1147,400 @@ impl VisitMut for StrReplace {
                         }
                     }
 
-                    let mem_ctx = MemEncCtx {
-                        ctx: encrypt_memory::<XChaCha20Poly1305>(s.value().as_bytes()),
-                        needs_owned_str: true,
-                    };
-                    let output = quote! {
-                        {
-                            #mem_ctx
-                        }
+                    let output =
+                        self.encrypt_literal(s.span(), s.value().as_bytes(), Lookup::Owned);
+                    node.init = Some((init.0, Box::new(Expr::Block(output))));
+                    return;
+                }
+            } else if let Expr::Macro(mac) = &*init.1 {
+                //Same lifetime issues as literals, so the included contents get owned here too
+                if let Pat::Type(ty) = &node.pat {
+                    if let Type::Reference(_) = *ty.ty {
+                        return;
+                    }
+                }
+
+                //Arrays are borrowed rather than owned, the binding extends the temporary
+                if let Some((data, is_bytes, span)) = self.read_include(mac) {
+                    let lookup = if is_bytes {
+                        Lookup::BorrowedArray
+                    } else {
+                        Lookup::Owned
                     };
-                    let output = syn::parse2::<ExprBlock>(output).unwrap();
+                    let output = self.encrypt_literal(span, &data, lookup);
                     node.init = Some((init.0, Box::new(Expr::Block(output))));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod scheme_attribute_tests {
+    use crate::strencrypt::*;
+
+    #[test]
+    fn bad_schemes() {
+        let mut file = syn::parse_file(
+            r#"
+            #[string_scheme = "rot13"]
+            fn rotated() {
+                println!("rotated");
+            }
+            fn main() {
+                #[string_scheme(xor)]
+                let listed = "listed";
+            }
+            "#,
+        )
+        .unwrap();
+        encrypt_strings(&mut file, None, &StrEncConfig::default());
+        let output: String = prettyplease::unparse(&file).split_whitespace().collect();
+
+        //Reported in place of the override, the literals still get the default scheme
+        assert!(output
+            .contains("fnrotated(){compile_error!(\"Unknownstringscheme\\\"rot13\\\",expected"));
+        assert!(output.contains(
+            "compile_error!(\"Expected#[string_scheme=\\\"...\\\"],found#[string_scheme(xor)]\");\
+             letlisted"
+        ));
+        assert!(!output.contains("\"rotated\""));
+        assert!(!output.contains("\"listed\""));
+    }
+}
+
+pub fn encrypt_strings(
+    input: &mut File,
+    source_path: Option<&Path>,
+    config: &StrEncConfig,
+) -> Vec<SkippedLiteral> {
+    let mut state = StrReplace::new(config, source_path);
+    state.visit_file_mut(input);
+
+    if !state.entries.is_empty() {
+        input.items.extend(state.table_items());
+    }
+    state.skipped
+}
+
+//Format strings made of nothing but placeholders, like "{}", leave no text in the binary
+fn has_format_text(format_string: &ExprLit) -> bool {
+    let value = match &format_string.lit {
+        Lit::Str(s) => s.value(),
+        _ => return true,
+    };
+    let mut chars = value.chars().peekable();
+    let mut in_placeholder = false;
+    while let Some(c) = chars.next() {
+        match c {
+            //Escaped braces are text
+            '{' | '}' if !in_placeholder && chars.peek() == Some(&c) => return true,
+            '{' => in_placeholder = true,
+            '}' => in_placeholder = false,
+            _ if !in_placeholder => return true,
+            _ => (),
+        }
+    }
+    false
+}
+
+//Attributes that never make it into the binary
+const IGNORED_ATTRS: [&str; 2] = ["doc", SCHEME_ATTR_NAME];
+
+/*
+ * Walks the original source and finds every string literal, along with the outermost construct
+ * that would stop StrReplace from reaching it
+ * Has to mirror the skips in the VisitMut impl above
+ */
+struct LiteralFinder {
+    context: Vec<SkipReason>,
+    found: Vec<(usize, usize, Option<SkipReason>)>,
+}
+
+impl LiteralFinder {
+    fn push_literal(&mut self, span: proc_macro2::Span) {
+        let start = span.start();
+        self.found
+            .push((start.line, start.column, self.context.first().copied()));
+    }
+
+    fn with_context<F>(&mut self, reason: SkipReason, visit: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        self.context.push(reason);
+        visit(self);
+        self.context.pop();
+    }
+
+    //Macro bodies and attribute arguments are raw tokens rather than syntax tree nodes
+    fn visit_tokens(&mut self, tokens: TokenStream) {
+        for token in tokens {
+            match token {
+                proc_macro2::TokenTree::Group(group) => self.visit_tokens(group.stream()),
+                proc_macro2::TokenTree::Literal(literal) => {
+                    let span = literal.span();
+                    let tokens = proc_macro2::TokenTree::Literal(literal).into();
+                    if let Ok(Lit::Str(_) | Lit::ByteStr(_)) = syn::parse2::<Lit>(tokens) {
+                        self.push_literal(span);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<'ast> syn::visit::Visit<'ast> for LiteralFinder {
+    fn visit_lit_str(&mut self, node: &'ast LitStr) {
+        self.push_literal(node.span());
+    }
+
+    fn visit_lit_byte_str(&mut self, node: &'ast LitByteStr) {
+        self.push_literal(node.span());
+    }
+
+    fn visit_item_const(&mut self, node: &'ast ItemConst) {
+        self.with_context(SkipReason::ConstItem, |finder| {
+            syn::visit::visit_item_const(finder, node)
+        });
+    }
+
+    fn visit_item_static(&mut self, node: &'ast ItemStatic) {
+        self.with_context(SkipReason::ConstItem, |finder| {
+            syn::visit::visit_item_static(finder, node)
+        });
+    }
+
+    fn visit_expr_call(&mut self, node: &'ast ExprCall) {
+        self.with_context(SkipReason::CallArgument, |finder| {
+            syn::visit::visit_expr_call(finder, node)
+        });
+    }
+
+    fn visit_expr_method_call(&mut self, node: &'ast ExprMethodCall) {
+        self.with_context(SkipReason::CallArgument, |finder| {
+            syn::visit::visit_expr_method_call(finder, node)
+        });
+    }
+
+    fn visit_pat(&mut self, node: &'ast Pat) {
+        self.with_context(SkipReason::MatchPattern, |finder| {
+            syn::visit::visit_pat(finder, node)
+        });
+    }
+
+    fn visit_attribute(&mut self, node: &'ast Attribute) {
+        if IGNORED_ATTRS.iter().any(|name| node.path.is_ident(name)) {
+            return;
+        }
+        self.with_context(SkipReason::Attribute, |finder| {
+            finder.visit_tokens(node.tokens.to_owned())
+        });
+    }
+
+    fn visit_local(&mut self, node: &'ast Local) {
+        for attr in &node.attrs {
+            self.visit_attribute(attr);
+        }
+        self.visit_pat(&node.pat);
+        if let Some((_, init)) = &node.init {
+            match &**init {
+                //The only initializers StrReplace rewrites, see visit_local_mut
+                Expr::Lit(_) | Expr::Macro(_) => self.visit_expr(init),
+                _ => {
+                    self.with_context(SkipReason::LetInitializer, |finder| finder.visit_expr(init))
+                }
+            }
+        }
+    }
+
+    fn visit_macro(&mut self, node: &'ast Macro) {
+        let macro_path = node
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident.to_string())
+            .unwrap_or_default();
+
+        match macro_path.as_str() {
+            "include_str" | "include_bytes" => {
+                self.visit_tokens(node.tokens.to_owned());
+                return;
+            }
+            "println" | "eprintln" | "format" | "concat" if node.path.get_ident().is_some() => {
+                if let Ok(parsed) = node.parse_body::<FormatArgs>() {
+                    //Format strings with placeholders stay, only the arguments can be replaced
+                    if has_format_text(&parsed.format_string) {
+                        self.with_context(SkipReason::UnsupportedMacro, |finder| {
+                            finder.visit_expr_lit(&parsed.format_string)
+                        });
+                    }
+                    for arg in &parsed.positional_args {
+                        self.visit_expr(arg);
+                    }
+                    self.with_context(SkipReason::UnsupportedMacro, |finder| {
+                        parsed
+                            .named_args
+                            .iter()
+                            .for_each(|(_, arg)| finder.visit_expr(arg))
+                    });
                     return;
                 }
             }
+            _ => (),
         }
+
+        self.with_context(SkipReason::UnsupportedMacro, |finder| {
+            finder.visit_tokens(node.tokens.to_owned())
+        });
     }
 }
 
-pub fn encrypt_strings(input: &mut File) {
-    StrReplace.visit_file_mut(input);
+//A single string literal, reason is None if it was encrypted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiteralAudit {
+    pub line: usize,
+    pub column: usize,
+    pub reason: Option<SkipReason>,
+}
+
+/*
+ * Analysis mode for the string encryption pass
+ * Runs StrReplace over a copy of the file and reports what happened to every literal in it
+ */
+pub fn audit_strings(
+    input: &File,
+    source_path: Option<&Path>,
+    config: &StrEncConfig,
+) -> Vec<LiteralAudit> {
+    let mut output = input.to_owned();
+    let mut state = StrReplace::new(config, source_path);
+    state.visit_file_mut(&mut output);
+
+    let mut finder = LiteralFinder {
+        context: Vec::new(),
+        found: Vec::new(),
+    };
+    syn::visit::Visit::visit_file(&mut finder, input);
+
+    finder
+        .found
+        .into_iter()
+        .map(|(line, column, context)| {
+            let reason = if state.encrypted.contains(&(line, column)) {
+                None
+            } else {
+                //Skips StrReplace reported itself are more specific than the surrounding context
+                let reported = state
+                    .skipped
+                    .iter()
+                    .find(|skipped| skipped.line == line && skipped.column == column)
+                    .map(|skipped| skipped.reason);
+                Some(
+                    reported
+                        .or(context)
+                        .unwrap_or(SkipReason::UnsupportedExpression),
+                )
+            };
+            LiteralAudit {
+                line,
+                column,
+                reason,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod audit_tests {
+    use crate::strencrypt::*;
+
+    #[test]
+    fn reasons() {
+        let source = r#"
+            const GREETING: &str = "const";
+            #[cfg(feature = "attribute")]
+            fn main() {
+                let plain = "encrypted";
+                let typed: &[u8; 4] = b"typd";
+                let leaked: &'static str = "leak";
+                let init = if plain.is_empty() { "let" } else { "init" };
+                takes("call");
+                println!("{}", "macro argument");
+                println!("{} placeholder", plain);
+                vec!["opaque"];
+                match plain {
+                    "arm" => (),
+                    _ => (),
+                }
+            }
+        "#;
+        let file = syn::parse_file(source).unwrap();
+        let audit = audit_strings(&file, None, &StrEncConfig::default());
+
+        let reasons: Vec<(usize, Option<SkipReason>)> = audit
+            .iter()
+            .map(|literal| (literal.line, literal.reason))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (2, Some(SkipReason::ConstItem)),
+                (3, Some(SkipReason::Attribute)),
+                (5, None),
+                (6, Some(SkipReason::ExplicitReferenceType)),
+                (7, Some(SkipReason::ExplicitReferenceType)),
+                (8, Some(SkipReason::LetInitializer)),
+                (8, Some(SkipReason::LetInitializer)),
+                (9, Some(SkipReason::CallArgument)),
+                (10, None),
+                (11, Some(SkipReason::UnsupportedMacro)),
+                (12, Some(SkipReason::UnsupportedMacro)),
+                (14, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn panic_messages() {
+        let source = r#"
+            fn main() {
+                let value = lookup().expect("let expect");
+                lookup().unwrap_or(0).to_string().len();
+                lookup().expect("chained expect").to_string();
+                assert!(value > 0, "assert message");
+                assert_eq!(value, 1, "{} placeholder", value);
+                panic!("panic message");
+            }
+        "#;
+        let file = syn::parse_file(source).unwrap();
+        let lines = |config: &StrEncConfig| -> Vec<(usize, Option<SkipReason>)> {
+            audit_strings(&file, None, config)
+                .iter()
+                .map(|literal| (literal.line, literal.reason))
+                .collect()
+        };
+
+        let unsupported = Some(SkipReason::UnsupportedMacro);
+        assert_eq!(
+            lines(&StrEncConfig::default()),
+            vec![
+                (3, Some(SkipReason::LetInitializer)),
+                (5, Some(SkipReason::CallArgument)),
+                (6, unsupported),
+                (7, unsupported),
+                (8, unsupported),
+            ]
+        );
+
+        let config = StrEncConfig {
+            panic_messages: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            lines(&config),
+            vec![(3, None), (5, None), (6, None), (7, unsupported), (8, None)]
+        );
+    }
 }
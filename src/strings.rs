@@ -0,0 +1,485 @@
+use rand::distributions::Alphanumeric;
+use rand::prelude::*;
+use rand::rngs::OsRng;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::crypto::{SecretString, KEY_SHARE_SIZE};
+
+//Workaround to self obfuscate (since we can't add ourselves as a dependency)
+#[allow(unused_imports)]
+use crate as r2d2;
+
+/*
+ * Runtime support for encrypted string literals
+ * Everything in here is referenced by generated code, so paths and signatures are effectively
+ * public API for obfuscated crates
+ */
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum CachePolicy {
+    //Decrypt on first use, keep the plaintext for the life of the program
+    Forever,
+    //Decrypt on first use in each thread, plaintext is dropped alongside the thread
+    PerThread,
+    //Hand out the same plaintext N times before throwing it away and decrypting again
+    EveryN(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCachePolicyError(String);
+
+impl fmt::Display for ParseCachePolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown cache policy \"{}\", expected \"forever\", \"thread\" or \"every:N\"",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseCachePolicyError {}
+
+impl FromStr for CachePolicy {
+    type Err = ParseCachePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forever" => Ok(CachePolicy::Forever),
+            "thread" => Ok(CachePolicy::PerThread),
+            _ => s
+                .strip_prefix("every:")
+                .and_then(|count| count.parse::<usize>().ok())
+                .filter(|count| *count > 0)
+                .map(CachePolicy::EveryN)
+                .ok_or_else(|| ParseCachePolicyError(s.to_string())),
+        }
+    }
+}
+
+thread_local! {
+    //Keyed by the address of the owning StringCache, which is always a static
+    static THREAD_CACHE: RefCell<HashMap<usize, Arc<SecretString>>> = RefCell::new(HashMap::new());
+}
+
+/*
+ * Decrypted value cache for a single literal site
+ * Generated string tables hold one of these per encrypted literal, so hot loops only pay for
+ * decryption as often as the policy allows
+ */
+pub struct StringCache {
+    policy: CachePolicy,
+    //Cached plaintext and the number of times it has been handed out
+    shared: Mutex<Option<(Arc<SecretString>, usize)>>,
+}
+
+impl StringCache {
+    pub const fn new(policy: CachePolicy) -> Self {
+        StringCache {
+            policy,
+            shared: Mutex::new(None),
+        }
+    }
+
+    pub fn get<F>(&'static self, decrypt: F) -> Arc<SecretString>
+    where
+        F: FnOnce() -> SecretString,
+    {
+        match self.policy {
+            CachePolicy::Forever => self.get_shared(decrypt, None),
+            CachePolicy::EveryN(limit) => self.get_shared(decrypt, Some(limit)),
+            CachePolicy::PerThread => {
+                let key = self as *const Self as usize;
+                THREAD_CACHE.with(|cache| {
+                    cache
+                        .borrow_mut()
+                        .entry(key)
+                        .or_insert_with(|| Arc::new(decrypt()))
+                        .clone()
+                })
+            }
+        }
+    }
+
+    fn get_shared<F>(&self, decrypt: F, limit: Option<usize>) -> Arc<SecretString>
+    where
+        F: FnOnce() -> SecretString,
+    {
+        //Poisoning only means another thread panicked mid-decrypt, the slot is still usable
+        let mut slot = match self.shared.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Some((_, uses)) = slot.as_ref() {
+            if limit.map_or(false, |limit| *uses >= limit) {
+                //Outstanding references keep the old copy alive until they're done with it
+                *slot = None;
+            }
+        }
+
+        let (value, uses) = slot.get_or_insert_with(|| (Arc::new(decrypt()), 0));
+        *uses += 1;
+        value.clone()
+    }
+}
+
+impl fmt::Debug for StringCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        //Intentionally leaves out the cached plaintext
+        f.debug_struct("StringCache")
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+//Runtime half of a string encryption scheme, see strencrypt::StringScheme
+pub type DecryptFn = fn(&[&[u8; KEY_SHARE_SIZE]], &[u8], &[u8]) -> Option<Vec<u8>>;
+
+/*
+ * Stands in for the plaintext of a literal that failed to decrypt, given the plaintext length
+ * Either diverges or returns valid UTF-8, see strencrypt::DecryptFailure
+ */
+pub type FailureFn = fn(usize) -> Vec<u8>;
+
+pub fn fail_panic(_len: usize) -> Vec<u8> {
+    panic!("Corrupted data");
+}
+
+pub fn fail_abort(_len: usize) -> Vec<u8> {
+    std::process::abort();
+}
+
+pub fn fail_garbage(len: usize) -> Vec<u8> {
+    OsRng.sample_iter(Alphanumeric).take(len).collect()
+}
+
+//A single encrypted literal
+pub struct StringEntry {
+    pub decrypt: DecryptFn,
+    pub salt: &'static [u8],
+    pub ciphertext: &'static [u8],
+    //Plaintext length, authenticated schemes make the ciphertext longer by their tag
+    pub len: usize,
+}
+
+/*
+ * Every encrypted literal in a source file, gathered into a single static
+ * Literal sites only hold an index into the table, which keeps the generated code small
+ */
+pub struct StringTable {
+    //Split master secret the entry keys are derived from
+    pub shares: &'static [&'static [u8; KEY_SHARE_SIZE]],
+    pub entries: &'static [StringEntry],
+    //One cache per entry, only present when caching is enabled
+    pub caches: Option<&'static [StringCache]>,
+    pub on_failure: FailureFn,
+}
+
+fn decrypt_entry(table: &StringTable, index: usize) -> Vec<u8> {
+    let entry = &table.entries[index];
+    match (entry.decrypt)(table.shares, entry.salt, entry.ciphertext) {
+        Some(plaintext) => plaintext,
+        None => (table.on_failure)(entry.len),
+    }
+}
+
+//Unauthenticated schemes can't tell tampering apart, so invalid UTF-8 counts as a failure too
+fn decrypt_str_entry(table: &StringTable, index: usize) -> String {
+    String::from_utf8(decrypt_entry(table, index))
+        .or_else(|_| String::from_utf8((table.on_failure)(table.entries[index].len)))
+        .unwrap()
+}
+
+pub fn get(table: &StringTable, index: usize) -> SecretString {
+    SecretString::from(decrypt_str_entry(table, index))
+}
+
+pub fn get_bytes(table: &StringTable, index: usize) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(decrypt_entry(table, index))
+}
+
+//include_bytes! contents, which keep their length in the type
+pub fn get_byte_array<const N: usize>(table: &StringTable, index: usize) -> Zeroizing<[u8; N]> {
+    let mut array = Zeroizing::new([0u8; N]);
+    array.copy_from_slice(&get_bytes(table, index));
+    array
+}
+
+pub fn get_cached(table: &'static StringTable, index: usize) -> Arc<SecretString> {
+    let caches = table
+        .caches
+        .expect("String table was generated without caches");
+    caches[index].get(|| get(table, index))
+}
+
+/*
+ * Short string literal rebuilt on the stack one masked byte write at a time
+ * Generated code fills it in shuffled order, so the plaintext never sits in the binary as a run
+ * Wiped on drop like SecretString
+ */
+pub struct StackString<const N: usize> {
+    data: [u8; N],
+}
+
+impl<const N: usize> StackString<N> {
+    pub fn zeroed() -> Self {
+        StackString { data: [0u8; N] }
+    }
+
+    pub fn set(&mut self, index: usize, byte: u8) {
+        self.data[index] = byte;
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.data).unwrap()
+    }
+}
+
+impl<const N: usize> Drop for StackString<N> {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+impl<const N: usize> Deref for StackString<N> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> AsRef<str> for StackString<N> {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for StackString<N> {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl<const N: usize> fmt::Display for StackString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> fmt::Debug for StackString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> PartialEq<str> for StackString<N> {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<const N: usize> PartialEq<&str> for StackString<N> {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+//Byte string counterpart of StackString, contents don't have to be valid UTF-8
+pub struct StackBytes<const N: usize> {
+    data: [u8; N],
+}
+
+impl<const N: usize> StackBytes<N> {
+    pub fn zeroed() -> Self {
+        StackBytes { data: [0u8; N] }
+    }
+
+    pub fn set(&mut self, index: usize, byte: u8) {
+        self.data[index] = byte;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl<const N: usize> Drop for StackBytes<N> {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+impl<const N: usize> Deref for StackBytes<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for StackBytes<N> {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl<const N: usize> fmt::Debug for StackBytes<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.data, f)
+    }
+}
+
+/*
+ * Comparison used by match arms whose string literal patterns were rewritten into guards
+ * Runs in constant time with respect to the contents, so the literal can't be recovered one byte
+ * at a time by timing the dispatch
+ */
+pub fn ct_eq<T>(value: &T, expected: &str) -> bool
+where
+    T: AsRef<[u8]> + ?Sized,
+{
+    bool::from(value.as_ref().ct_eq(expected.as_bytes()))
+}
+
+#[cfg(test)]
+mod string_table_tests {
+    use crate::crypto::*;
+    use crate::strings::*;
+
+    static SHARE_A: [u8; KEY_SHARE_SIZE] = [0x5A; KEY_SHARE_SIZE];
+    static SHARE_B: [u8; KEY_SHARE_SIZE] = [0xC3; KEY_SHARE_SIZE];
+    static SHARES: [&[u8; KEY_SHARE_SIZE]; 2] = [&SHARE_A, &SHARE_B];
+
+    #[test]
+    fn lookup() {
+        let ciphertext = rolling_xor_derived(&SHARES, b"salt", b"FizzBuzz");
+        let entries = vec![StringEntry {
+            decrypt: |shares, salt, data| Some(rolling_xor_derived(shares, salt, data)),
+            salt: b"salt",
+            ciphertext: Box::leak(ciphertext.into_boxed_slice()),
+            len: 8,
+        }];
+        let table: &'static StringTable = Box::leak(Box::new(StringTable {
+            shares: &SHARES,
+            entries: Box::leak(entries.into_boxed_slice()),
+            caches: Some(Box::leak(Box::new([StringCache::new(CachePolicy::Forever)]))),
+            on_failure: fail_panic,
+        }));
+
+        assert_eq!(get(table, 0), "FizzBuzz");
+        assert_eq!(*get_cached(table, 0), "FizzBuzz");
+        assert_eq!(get_bytes(table, 0).as_slice(), b"FizzBuzz");
+        assert_eq!(*get_byte_array::<8>(table, 0), *b"FizzBuzz");
+        assert!(ct_eq("FizzBuzz", &get(table, 0)));
+        assert!(!ct_eq("FizzBuzzFizz", &get(table, 0)));
+    }
+
+    fn tampered_table(on_failure: FailureFn) -> StringTable {
+        let mut ciphertext =
+            encrypt_memory_derived::<XChaCha20Poly1305>(&SHARES, b"salt", b"FizzBuzz").ciphertext;
+        ciphertext[0] ^= 1;
+        let entries = vec![StringEntry {
+            decrypt: try_decrypt_memory_derived::<XChaCha20Poly1305>,
+            salt: b"salt",
+            ciphertext: Box::leak(ciphertext.into_boxed_slice()),
+            len: 8,
+        }];
+        StringTable {
+            shares: &SHARES,
+            entries: Box::leak(entries.into_boxed_slice()),
+            caches: None,
+            on_failure,
+        }
+    }
+
+    #[test]
+    fn failure_garbage() {
+        let table = tampered_table(fail_garbage);
+        let value = get(&table, 0);
+        assert_ne!(value, "FizzBuzz");
+        assert_eq!(value.len(), "FizzBuzz".len());
+    }
+
+    #[test]
+    #[should_panic(expected = "Corrupted data")]
+    fn failure_panic() {
+        get(&tampered_table(fail_panic), 0);
+    }
+}
+
+#[cfg(test)]
+mod stack_string_tests {
+    use crate::strings::*;
+
+    #[test]
+    fn build() {
+        let mut value = StackString::<8>::zeroed();
+        //Same as generated code, the order bytes are written in doesn't matter
+        for (index, byte) in b"FizzBuzz".iter().enumerate().rev() {
+            value.set(index, *byte);
+        }
+        assert_eq!(value, "FizzBuzz");
+        assert_eq!(value.len(), 8);
+        assert_eq!(format!("{}", value), "FizzBuzz");
+        assert!(ct_eq(&value, "FizzBuzz"));
+
+        let mut bytes = StackBytes::<2>::zeroed();
+        bytes.set(1, 0xFF);
+        bytes.set(0, 0x7F);
+        assert_eq!(bytes.as_bytes(), b"\x7f\xff");
+    }
+}
+
+#[cfg(test)]
+mod string_cache_tests {
+    use crate::crypto::SecretString;
+    use crate::strings::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn count_decryptions(cache: &'static StringCache, uses: usize) -> usize {
+        let decryptions = AtomicUsize::new(0);
+        for _ in 0..uses {
+            let value = cache.get(|| {
+                decryptions.fetch_add(1, Ordering::SeqCst);
+                SecretString::from("FizzBuzz")
+            });
+            assert_eq!(value.as_str(), "FizzBuzz");
+        }
+        decryptions.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn policies() {
+        static FOREVER: StringCache = StringCache::new(CachePolicy::Forever);
+        static PER_THREAD: StringCache = StringCache::new(CachePolicy::PerThread);
+        static EVERY_N: StringCache = StringCache::new(CachePolicy::EveryN(3));
+
+        assert_eq!(count_decryptions(&FOREVER, 10), 1);
+        assert_eq!(count_decryptions(&PER_THREAD, 10), 1);
+        assert_eq!(
+            std::thread::spawn(|| count_decryptions(&PER_THREAD, 10))
+                .join()
+                .unwrap(),
+            1
+        );
+        assert_eq!(count_decryptions(&EVERY_N, 10), 4);
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!("forever".parse(), Ok(CachePolicy::Forever));
+        assert_eq!("thread".parse(), Ok(CachePolicy::PerThread));
+        assert_eq!("every:16".parse(), Ok(CachePolicy::EveryN(16)));
+        assert!("every:0".parse::<CachePolicy>().is_err());
+        assert!("sometimes".parse::<CachePolicy>().is_err());
+    }
+}
@@ -0,0 +1,21 @@
+fn main() {
+    for i in 0..10 {
+        println!("Hot loop");
+        let x = "foobar";
+        println!("{} {}", x, i);
+    }
+
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            std::thread::spawn(|| {
+                for _ in 0..10 {
+                    println!("Worker loop");
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
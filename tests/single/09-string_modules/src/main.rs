@@ -0,0 +1,25 @@
+mod outer {
+    pub fn print() {
+        println!("Outer module");
+    }
+
+    pub mod inner {
+        pub fn print() {
+            let x = "Inner module";
+            println!("{}", x);
+        }
+    }
+}
+
+fn main() {
+    println!("Root module");
+    outer::print();
+    outer::inner::print();
+
+    mod scoped {
+        pub fn print() {
+            println!("Block scoped module");
+        }
+    }
+    scoped::print();
+}
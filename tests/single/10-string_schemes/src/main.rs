@@ -0,0 +1,27 @@
+struct Printer;
+
+impl Printer {
+    #[string_scheme = "chacha20"]
+    fn print(&self) {
+        println!("Stream cipher method");
+    }
+}
+
+#[string_scheme = "aes-gcm"]
+fn aes_gcm() {
+    println!("AES-GCM function");
+    let x = "AES-GCM binding";
+    println!("{}", x);
+}
+
+fn main() {
+    println!("Default scheme");
+    aes_gcm();
+    Printer.print();
+
+    for i in 0..10 {
+        #[string_scheme = "xor"]
+        let x = "Rolling XOR binding";
+        println!("{} {}", x, i);
+    }
+}
@@ -0,0 +1,38 @@
+fn dispatch(cmd: &str, verbose: bool) -> u32 {
+    match cmd {
+        "unlock" => 1,
+        "debug" if verbose => 2,
+        "debug" => 3,
+        "status" | "info" => 4,
+        _ => 0,
+    }
+}
+
+fn nested(cmd: Option<(&str, u32)>) -> u32 {
+    match cmd {
+        Some(("set", value)) => value,
+        Some((name @ "get", _)) => name.len() as u32,
+        Some(("a" | "b", _)) => 100,
+        _ => 0,
+    }
+}
+
+fn main() {
+    let results = [
+        dispatch("unlock", false),
+        dispatch("debug", true),
+        dispatch("debug", false),
+        dispatch("status", false),
+        dispatch("info", false),
+        dispatch("unlocked", false),
+        nested(Some(("set", 42))),
+        nested(Some(("get", 7))),
+        nested(Some(("b", 7))),
+        nested(None),
+    ];
+    println!("{:?}", results);
+
+    if results != [1, 2, 3, 4, 4, 0, 42, 3, 100, 0] {
+        std::process::exit(1);
+    }
+}
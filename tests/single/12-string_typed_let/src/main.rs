@@ -0,0 +1,33 @@
+fn leak() -> &'static str {
+    let name: &'static str = "static name";
+    name
+}
+
+fn leak_bytes() -> &'static [u8] {
+    let magic: &'static [u8] = b"\x7fELF";
+    magic
+}
+
+fn main() {
+    let plain: &str = "typed str";
+    let bytes: &[u8] = b"typed bytes";
+    let array: &[u8; 5] = b"array";
+    let mut count = 0;
+
+    for _ in 0..3 {
+        let looped: &str = "looped";
+        count += looped.len();
+    }
+
+    if plain != "typed str"
+        || bytes != b"typed bytes"
+        || array != b"array"
+        || leak() != "static name"
+        || leak_bytes() != b"\x7fELF"
+        || count != 18
+    {
+        std::process::exit(1);
+    }
+
+    println!("{} {} {}", plain, leak(), count);
+}
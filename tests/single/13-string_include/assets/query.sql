@@ -0,0 +1 @@
+SELECT name, secret FROM users WHERE id = ?;
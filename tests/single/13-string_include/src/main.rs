@@ -0,0 +1,32 @@
+mod templates {
+    pub fn greeting() -> String {
+        format!("{}", include_str!("greeting.html"))
+    }
+}
+
+//Only accepts what include_bytes! evaluates to, length included
+fn cert_trailer(cert: &[u8; 10]) -> u8 {
+    cert[9]
+}
+
+fn main() {
+    let query = include_str!("../assets/query.sql");
+    let cert = include_bytes!("../assets/cert.der");
+    let typed: &str = include_str!("../assets/query.sql");
+    let typed_cert: &[u8; 10] = include_bytes!("../assets/cert.der");
+    let static_cert: &'static [u8; 10] = include_bytes!("../assets/cert.der");
+
+    if !query.starts_with("SELECT name")
+        || typed.len() != query.len()
+        || cert.len() != 10
+        || cert[0] != 0x30
+        || cert_trailer(cert) != 0xFF
+        || typed_cert != static_cert
+        || include_bytes!("../assets/cert.der") != cert
+        || !templates::greeting().contains("<h1>")
+    {
+        std::process::exit(1);
+    }
+
+    println!("{}", include_str!("../assets/query.sql"));
+}
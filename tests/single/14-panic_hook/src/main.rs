@@ -0,0 +1,18 @@
+fn lookup(key: &str) -> Option<u32> {
+    match key {
+        "answer" => Some(42),
+        _ => None,
+    }
+}
+
+fn main() {
+    let answer = lookup("answer").expect("the answer went missing");
+    assert_eq!(answer, 42, "the answer changed");
+    if answer != 42 {
+        unreachable!("answers never change");
+    }
+    println!("The answer is {}", answer);
+
+    //Release builds abort on panic, so this is the last thing that happens
+    lookup("question").expect("the secret value was missing");
+}
@@ -0,0 +1,5 @@
+//The test driver corrupts the ciphertext of this literal in the built binary
+fn main() {
+    let secret = "Patched out of the binary";
+    println!("{}", secret);
+}
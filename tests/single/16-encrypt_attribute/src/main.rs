@@ -0,0 +1,28 @@
+struct Credentials {
+    user: String,
+    #[r2d2::encrypt]
+    token: String,
+}
+
+impl Credentials {
+    fn masked(&mut self) -> String {
+        format!("{}:{}", self.user, "*".repeat(self.token.len()))
+    }
+}
+
+fn main() {
+    #[encrypt]
+    let pin: u32 = 1234;
+    let mut credentials = Credentials {
+        user: String::from("r2d2"),
+        token: String::from("hunter2"),
+    };
+    assert_eq!(credentials.masked(), "r2d2:*******");
+
+    credentials.token.push('!');
+    assert_eq!(credentials.token, "hunter2!");
+    for digit in 1..=4 {
+        assert_eq!(pin / 10u32.pow(4 - digit) % 10, digit);
+    }
+    println!("Authenticated as {}", credentials.user);
+}
@@ -0,0 +1,53 @@
+use std::num::ParseIntError;
+
+#[r2d2::scrub_stack]
+fn parse_pin(input: &str) -> Result<u32, ParseIntError> {
+    if input.is_empty() {
+        return Ok(0);
+    }
+    let pin: u32 = input.trim().parse()?;
+    Ok(pin)
+}
+
+struct Vault {
+    secret: Vec<u8>,
+}
+
+impl Vault {
+    #[scrub_stack(4096)]
+    fn checksum(&self) -> u32 {
+        self.secret.iter().map(|byte| *byte as u32).sum()
+    }
+}
+
+trait Unlock {
+    fn code(&self) -> u32;
+
+    #[scrub_stack]
+    fn unlock(&self, attempt: u32) -> bool {
+        if attempt != self.code() {
+            return false;
+        }
+        true
+    }
+}
+
+impl Unlock for Vault {
+    fn code(&self) -> u32 {
+        self.checksum()
+    }
+}
+
+fn main() {
+    assert_eq!(parse_pin(""), Ok(0));
+    assert_eq!(parse_pin(" 1234 "), Ok(1234));
+    assert!(parse_pin("12a4").is_err());
+
+    let vault = Vault {
+        secret: vec![1, 2, 3],
+    };
+    assert_eq!(vault.checksum(), 6);
+    assert!(vault.unlock(6));
+    assert!(!vault.unlock(7));
+    println!("Vault unlocked");
+}
@@ -5,7 +5,7 @@ use std::fs;
 use std::fs::DirBuilder;
 use std::io;
 use std::io::Write;
-use std::process::{Command, ExitStatus};
+use std::process::{Command, ExitStatus, Output};
 use std::sync::{Mutex, MutexGuard};
 
 const TEST_DIRECTORY: &str = ".r2d2_test_dir";
@@ -38,12 +38,19 @@ fn compile_test(path: &str) -> ExitStatus {
         need_obfuscate: true,
         obfuscate_dir: Some(path),
         stream_output: false,
+        obfuscate_config: ObfuscateConfig::default(),
+        scan_binaries: false,
+        scrub_paths: false,
     };
 
     build(&config).unwrap()
 }
 
 fn functional_test(path: &str) -> ExitStatus {
+    functional_test_with_config(path, ObfuscateConfig::default())
+}
+
+fn functional_test_with_config(path: &str, obfuscate_config: ObfuscateConfig) -> ExitStatus {
     let _lock = lock_filesystem();
 
     let config = R2D2Config {
@@ -53,6 +60,9 @@ fn functional_test(path: &str) -> ExitStatus {
         need_obfuscate: true,
         obfuscate_dir: Some(path),
         stream_output: false,
+        obfuscate_config,
+        scan_binaries: false,
+        scrub_paths: false,
     };
 
     build(&config).unwrap()
@@ -142,6 +152,443 @@ mod single {
         let status = functional_test("tests/single/07-assert_shatter");
         assert!(status.success());
     }
+
+    #[test]
+    fn string_cache_functional() {
+        for policy in [
+            r2d2::strings::CachePolicy::Forever,
+            r2d2::strings::CachePolicy::PerThread,
+            r2d2::strings::CachePolicy::EveryN(3),
+        ] {
+            let mut config = ObfuscateConfig::default();
+            config.strings.cache_policy = Some(policy);
+            let status = functional_test_with_config("tests/single/08-string_cache", config);
+            assert!(status.success());
+        }
+    }
+
+    #[test]
+    fn string_stack_functional() {
+        for path in [
+            "tests/single/02-prints",
+            "tests/single/11-string_match",
+            "tests/single/12-string_typed_let",
+        ] {
+            let mut config = ObfuscateConfig::default();
+            config.strings.stack_threshold = 16;
+            let status = functional_test_with_config(path, config);
+            assert!(status.success());
+        }
+    }
+
+    #[test]
+    fn string_failure_functional() {
+        for policy in [
+            DecryptFailure::Panic,
+            DecryptFailure::Garbage,
+            DecryptFailure::Abort,
+            DecryptFailure::Shatter,
+        ] {
+            let mut config = ObfuscateConfig::default();
+            config.strings.on_failure = policy;
+            let status = functional_test_with_config("tests/single/02-prints", config);
+            assert!(status.success());
+        }
+    }
+
+    //The literal in 15-string_failure
+    const FAILURE_SECRET: &str = "Patched out of the binary";
+
+    //Ciphertext of the string table entry with a given plaintext length
+    struct CiphertextFinder {
+        len: usize,
+        ciphertext: Option<Vec<u8>>,
+    }
+
+    impl<'ast> syn::visit::Visit<'ast> for CiphertextFinder {
+        fn visit_expr_struct(&mut self, node: &'ast syn::ExprStruct) {
+            let field = |name: &str| {
+                node.fields
+                    .iter()
+                    .find_map(|field| match (&field.member, &field.expr) {
+                        (syn::Member::Named(ident), syn::Expr::Lit(lit)) if ident == name => {
+                            Some(lit.lit.to_owned())
+                        }
+                        _ => None,
+                    })
+            };
+            if let (Some(syn::Lit::Int(len)), Some(syn::Lit::ByteStr(ciphertext))) =
+                (field("len"), field("ciphertext"))
+            {
+                if len.base10_parse::<usize>().unwrap() == self.len {
+                    self.ciphertext = Some(ciphertext.value());
+                }
+            }
+            syn::visit::visit_expr_struct(self, node);
+        }
+    }
+
+    /*
+     * Builds 15-string_failure, flips a bit of the literal's ciphertext in the binary, the way
+     * someone patching it would, and runs the patched copy
+     */
+    fn string_failure_run(on_failure: DecryptFailure) -> Output {
+        let _lock = lock_filesystem();
+
+        let mut obfuscate_config = ObfuscateConfig::default();
+        obfuscate_config.strings.on_failure = on_failure;
+        let config = R2D2Config {
+            dest_name: Some(TEST_DIRECTORY),
+            cargo_args: None,
+            need_run: false,
+            need_obfuscate: true,
+            obfuscate_dir: Some("tests/single/15-string_failure"),
+            stream_output: false,
+            obfuscate_config,
+            scan_binaries: false,
+            scrub_paths: false,
+        };
+        assert!(build(&config).unwrap().success());
+
+        let source = generate_temp_folder_name(Some(TEST_DIRECTORY))
+            .join("tests/single/15-string_failure/src/main.rs");
+        let mut finder = CiphertextFinder {
+            len: FAILURE_SECRET.len(),
+            ciphertext: None,
+        };
+        syn::visit::Visit::visit_file(
+            &mut finder,
+            &syn::parse_file(&fs::read_to_string(&source).unwrap()).unwrap(),
+        );
+        let ciphertext = finder.ciphertext.unwrap();
+
+        let binary = get_src_dir()
+            .target_dir
+            .join("debug")
+            .join(format!("string_failure{}", std::env::consts::EXE_SUFFIX));
+        let mut contents = fs::read(&binary).unwrap();
+        let offset = contents
+            .windows(ciphertext.len())
+            .position(|window| window == ciphertext)
+            .unwrap();
+        contents[offset] ^= 1;
+
+        //Copying first keeps the binary executable
+        let patched = binary.with_file_name(format!(
+            "string_failure_patched{}",
+            std::env::consts::EXE_SUFFIX
+        ));
+        fs::copy(&binary, &patched).unwrap();
+        fs::write(&patched, &contents).unwrap();
+        let output = Command::new(&patched).output().unwrap();
+        assert!(!String::from_utf8_lossy(&output.stdout).contains(FAILURE_SECRET));
+        output
+    }
+
+    #[test]
+    fn string_failure_panic() {
+        let output = string_failure_run(DecryptFailure::Panic);
+        assert_eq!(output.status.code(), Some(101));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Corrupted data"));
+    }
+
+    #[test]
+    fn string_failure_garbage() {
+        let output = string_failure_run(DecryptFailure::Garbage);
+        assert!(output.status.success());
+        let garbage = String::from_utf8(output.stdout).unwrap();
+        let garbage = garbage.trim_end();
+        assert_eq!(garbage.len(), FAILURE_SECRET.len());
+        assert!(garbage.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+
+    #[test]
+    fn string_failure_abort() {
+        let output = string_failure_run(DecryptFailure::Abort);
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+        assert!(output.stderr.is_empty());
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            assert_eq!(output.status.signal(), Some(6));
+        }
+    }
+
+    #[test]
+    fn string_failure_shatter() {
+        //Wherever the rabbit hole leads, it mustn't carry on as if nothing happened
+        let output = string_failure_run(DecryptFailure::Shatter);
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+    }
+
+    #[test]
+    fn zeroizing_allocator_functional() {
+        for path in ["tests/single/02-prints", "tests/single/08-string_cache"] {
+            let mut config = ObfuscateConfig::default();
+            config.zeroizing_allocator = true;
+            let status = functional_test_with_config(path, config);
+            assert!(status.success());
+        }
+    }
+
+    #[test]
+    fn string_modules_compile() {
+        let status = compile_test("tests/single/09-string_modules");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn string_modules_functional() {
+        let status = functional_test("tests/single/09-string_modules");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn string_schemes_compile() {
+        let status = compile_test("tests/single/10-string_schemes");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn string_schemes_functional() {
+        for scheme in [
+            StringSchemeKind::XChaCha20Poly1305,
+            StringSchemeKind::Aes256Gcm,
+            StringSchemeKind::ChaCha20,
+            StringSchemeKind::RollingXor,
+        ] {
+            let mut config = ObfuscateConfig::default();
+            config.strings.scheme = scheme;
+            let status = functional_test_with_config("tests/single/10-string_schemes", config);
+            assert!(status.success());
+        }
+    }
+
+    #[test]
+    fn string_match_compile() {
+        let status = compile_test("tests/single/11-string_match");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn string_match_functional() {
+        let status = functional_test("tests/single/11-string_match");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn string_typed_let_compile() {
+        let status = compile_test("tests/single/12-string_typed_let");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn string_typed_let_functional() {
+        let status = functional_test("tests/single/12-string_typed_let");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn string_include_compile() {
+        let status = compile_test("tests/single/13-string_include");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn string_include_functional() {
+        let status = functional_test("tests/single/13-string_include");
+        assert!(status.success());
+    }
+
+    //Builds in release, where the hook is installed, and returns what the binary printed to stderr
+    fn panic_hook_run(panics: PanicMode) -> String {
+        let _lock = lock_filesystem();
+
+        let mut obfuscate_config = ObfuscateConfig::default();
+        obfuscate_config.strings.panic_messages = true;
+        obfuscate_config.panics = panics;
+        let config = R2D2Config {
+            dest_name: Some(TEST_DIRECTORY),
+            cargo_args: Some(vec!["--release"]),
+            need_run: false,
+            need_obfuscate: true,
+            obfuscate_dir: Some("tests/single/14-panic_hook"),
+            stream_output: false,
+            obfuscate_config,
+            scan_binaries: false,
+            scrub_paths: false,
+        };
+        assert!(build(&config).unwrap().success());
+
+        let binary = get_src_dir()
+            .target_dir
+            .join("release")
+            .join(format!("panic_hook{}", std::env::consts::EXE_SUFFIX));
+        let output = Command::new(&binary).output().unwrap();
+        assert!(!output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "The answer is 42\n");
+
+        let contents = fs::read(&binary).unwrap();
+        let messages = [
+            "the secret value was missing",
+            "the answer went missing",
+            "the answer changed",
+        ];
+        for message in messages {
+            assert!(!contents
+                .windows(message.len())
+                .any(|window| window == message.as_bytes()));
+        }
+        String::from_utf8(output.stderr).unwrap()
+    }
+
+    #[test]
+    fn panic_hook_suppress() {
+        let stderr = panic_hook_run(PanicMode::Suppress);
+        assert!(stderr.is_empty());
+    }
+
+    #[test]
+    fn panic_hook_encrypt() {
+        let key = [0x5a; r2d2::crypto::KEY_SHARE_SIZE];
+        let stderr = panic_hook_run(PanicMode::Encrypt(key));
+        assert!(!stderr.contains("secret value"));
+
+        let report = r2d2::panics::decrypt_report(&key, &stderr).unwrap();
+        assert!(report.contains("the secret value was missing"));
+    }
+
+    #[test]
+    fn encrypt_attribute_functional() {
+        let status = functional_test("tests/single/16-encrypt_attribute");
+        assert!(status.success());
+    }
+
+    #[test]
+    fn scrub_stack_functional() {
+        let status = functional_test("tests/single/17-scrub_stack");
+        assert!(status.success());
+    }
+}
+
+mod scan {
+    use crate::*;
+
+    #[test]
+    fn hello_world_scan() {
+        let _lock = lock_filesystem();
+
+        let config = R2D2Config {
+            dest_name: Some(TEST_DIRECTORY),
+            cargo_args: None,
+            need_run: false,
+            need_obfuscate: true,
+            obfuscate_dir: Some("tests/single/01-hello_world"),
+            stream_output: false,
+            obfuscate_config: ObfuscateConfig::default(),
+            scan_binaries: true,
+            scrub_paths: false,
+        };
+        assert!(build(&config).unwrap().success());
+
+        let src = get_src_dir();
+        let binary = src
+            .target_dir
+            .join("debug")
+            .join(format!("hello_world{}", std::env::consts::EXE_SUFFIX));
+        let leaks = scan_workspace_binary(&binary, &src.workspace_root).unwrap();
+        //Paths aren't scrubbed, so panic locations in the r2d2 runtime keep its source paths
+        assert!(leaks
+            .iter()
+            .any(|leak| leak.kind == NeedleKind::SourcePath && leak.text.starts_with("src/")));
+        assert!(!leaks.iter().any(|leak| leak.text.contains("Hello, world!")));
+    }
+
+    #[test]
+    fn prints_decoys() {
+        let _lock = lock_filesystem();
+
+        let words = vec![
+            String::from("https://decoy.example.net/api/v9/ingest"),
+            String::from("DECOY_SIGNING_KEY_0451"),
+        ];
+        let mut obfuscate_config = ObfuscateConfig::default();
+        obfuscate_config.decoys.enabled = true;
+        obfuscate_config.decoys.words = words.to_owned();
+        let config = R2D2Config {
+            dest_name: Some(TEST_DIRECTORY),
+            cargo_args: None,
+            need_run: true,
+            need_obfuscate: true,
+            obfuscate_dir: Some("tests/single/02-prints"),
+            stream_output: false,
+            obfuscate_config,
+            scan_binaries: false,
+            scrub_paths: false,
+        };
+        assert!(build(&config).unwrap().success());
+
+        let src = get_src_dir();
+        let report = ObfuscationReport::read(&src.target_dir).unwrap().unwrap();
+        assert!(!report.decoys.is_empty());
+        assert!(report.decoys.iter().all(|decoy| words.contains(decoy)));
+
+        //Planted in the binary, but never reported as leaks
+        let binary = src
+            .target_dir
+            .join("debug")
+            .join(format!("prints{}", std::env::consts::EXE_SUFFIX));
+        let contents = fs::read(&binary).unwrap();
+        for decoy in &report.decoys {
+            assert!(contents
+                .windows(decoy.len())
+                .any(|window| window == decoy.as_bytes()));
+        }
+        let leaks = scan_workspace_binary(&binary, &src.workspace_root).unwrap();
+        assert!(!leaks.iter().any(|leak| words.contains(&leak.text)));
+    }
+
+    //03-crazy only builds on linux, see single::linux
+    #[cfg(target_os = "linux")]
+    mod linux {
+        use crate::*;
+
+        fn source_path_leaks(scrub_paths: bool) -> usize {
+            let config = R2D2Config {
+                dest_name: Some(TEST_DIRECTORY),
+                cargo_args: None,
+                need_run: false,
+                need_obfuscate: true,
+                obfuscate_dir: Some("tests/single/03-crazy"),
+                stream_output: false,
+                obfuscate_config: ObfuscateConfig::default(),
+                scan_binaries: false,
+                scrub_paths,
+            };
+            assert!(build(&config).unwrap().success());
+
+            let src = get_src_dir();
+            let binary = src.target_dir.join("debug").join("crazy");
+            let leaks = scan_workspace_binary(&binary, &src.workspace_root).unwrap();
+            leaks
+                .iter()
+                .filter(|leak| {
+                    leak.kind == NeedleKind::SourcePath && leak.text.contains("03-crazy")
+                })
+                .count()
+        }
+
+        #[test]
+        fn crazy_scrub_paths() {
+            let _lock = lock_filesystem();
+
+            //The unwrap in main puts its location in the binary
+            assert!(source_path_leaks(false) > 0);
+            assert_eq!(source_path_leaks(true), 0);
+        }
+    }
 }
 
 mod complex {